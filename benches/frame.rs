@@ -0,0 +1,162 @@
+// Copyright (c) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright
+//       notice, this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#[macro_use]
+extern crate criterion;
+
+extern crate quiche;
+
+use std::borrow::Cow;
+
+use criterion::black_box;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+
+use quiche::frame::Frame;
+use quiche::frame::FrameOwned;
+use quiche::octets;
+use quiche::ranges;
+
+// Measures encoding and decoding of each frame separately. Throughput is
+// reported in encoded bytes, so it can be compared across frame sizes.
+fn bench_frames(c: &mut Criterion, name: &str,
+                frames: Vec<(usize, FrameOwned)>) {
+    let mut group = c.benchmark_group(name);
+
+    for (param, frame) in frames {
+        let mut encoded = Vec::new();
+        frame.append_to_vec(&mut encoded).unwrap();
+
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+        let mut out = vec![0; encoded.len()];
+
+        group.bench_with_input(BenchmarkId::new("to_bytes", param), &frame,
+                               |bench, frame| {
+            bench.iter(|| {
+                let mut b = octets::Bytes::new(&mut out);
+                black_box(frame.to_bytes(&mut b).unwrap())
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("from_bytes", param), &frame,
+                               |bench, frame| {
+            let mut buf = encoded.clone();
+
+            bench.iter(|| {
+                let mut b = octets::Bytes::new(&mut buf);
+                let parsed = Frame::from_bytes(&mut b).unwrap();
+
+                assert_eq!(parsed.wire_len(), frame.wire_len());
+                black_box(parsed);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn stream(c: &mut Criterion) {
+    let frames = [16, 256, 1200, 16384].iter().map(|len| {
+        (*len, FrameOwned::stream(4, 1230976, vec![0xaa; *len], false))
+    }).collect();
+
+    bench_frames(c, "stream", frames);
+}
+
+fn crypto(c: &mut Criterion) {
+    let frames = [16, 256, 1200].iter().map(|len| {
+        let frame = Frame::Crypto {
+            offset: 1230976,
+            data: Cow::Owned(vec![0xaa; *len]),
+        };
+
+        (*len, frame)
+    }).collect();
+
+    bench_frames(c, "crypto", frames);
+}
+
+fn ack(c: &mut Criterion) {
+    // The parameter is the number of disjoint ranges acknowledged.
+    let frames = [1, 16, 256].iter().map(|count| {
+        let mut ranges = ranges::RangeSet::default();
+
+        for i in 0..*count as u64 {
+            ranges.insert_range(i * 10..i * 10 + 5);
+        }
+
+        (*count, FrameOwned::ack(ranges, 25000, 3))
+    }).collect();
+
+    bench_frames(c, "ack", frames);
+}
+
+fn new_connection_id(c: &mut Criterion) {
+    let frames = [4, 8, 20].iter().map(|len| {
+        let frame = Frame::NewConnectionId {
+            seq_num: 123213,
+            conn_id: Cow::Owned(vec![0xba; *len]),
+            reset_token: Cow::Owned(vec![0x42; 16]),
+        };
+
+        (*len, frame)
+    }).collect();
+
+    bench_frames(c, "new_connection_id", frames);
+}
+
+// Measures duplicate detection on a set of received packets where every
+// other packet is missing, i.e. the worst case of one range per packet.
+fn range_set_contains(c: &mut Criterion) {
+    let mut group = c.benchmark_group("range_set_contains");
+
+    for count in [10, 1000, 10000].iter() {
+        let mut set = ranges::RangeSet::default();
+
+        for pn in 0..*count {
+            set.insert(pn * 2).unwrap();
+        }
+
+        group.bench_with_input(BenchmarkId::new("contains", count), &set,
+                               |bench, set| {
+            let mut pn = 0;
+
+            bench.iter(|| {
+                // Step through both present and missing packet numbers.
+                pn = (pn + 7919) % (count * 2);
+                black_box(set.contains(pn))
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, stream, crypto, ack, new_connection_id,
+                 range_set_contains);
+criterion_main!(benches);
@@ -1,6 +1,11 @@
 use std::env;
 
 fn main() {
+    // BoringSSL is only used by the TLS stack.
+    if env::var("CARGO_FEATURE_STD").is_err() {
+        return;
+    }
+
     let bssl_dir_default = String::from("./boringssl/.openssl/lib");
     let bssl_dir = env::var("QUICHE_BSSL_PATH").unwrap_or(bssl_dir_default);
 
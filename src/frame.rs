@@ -24,149 +24,717 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use core::cmp;
+use core::convert::TryFrom;
+
+use alloc::borrow::Cow;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use ::Result;
 use ::Error;
 
 use octets;
+use ranges;
 
-#[derive(PartialEq, Debug)]
-pub enum Frame<'a> {
+const MAX_CONN_ID_LEN: usize = 20;
+
+const RESET_TOKEN_LEN: usize = 16;
+
+const MAX_STREAM_OFFSET: u64 = octets::max_varint();
+
+// Extension frame types that aren't otherwise understood, but are known to
+// carry a varint length-prefixed body. These are parsed as UnknownExt frames
+// so that they can be forwarded verbatim.
+const LENGTH_PREFIXED_EXT_TYPES: &[u64] = &[0x3f00];
+
+// Transport error codes carried by CONNECTION_CLOSE frames.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum TransportError {
+    NoError,
+    InternalError,
+    ServerBusy,
+    FlowControlError,
+    StreamIdError,
+    StreamStateError,
+    FinalOffsetError,
+    FrameEncodingError,
+    TransportParameterError,
+    VersionNegotiationError,
+    ProtocolViolation,
+    InvalidMigration,
+
+    // TLS alert, as the low byte of the 0x1XX range.
+    CryptoError(u8),
+
+    // Any code not defined above.
+    Unknown(u16),
+}
+
+impl TransportError {
+    pub fn from_code(code: u16) -> TransportError {
+        match code {
+            0x0 => TransportError::NoError,
+            0x1 => TransportError::InternalError,
+            0x2 => TransportError::ServerBusy,
+            0x3 => TransportError::FlowControlError,
+            0x4 => TransportError::StreamIdError,
+            0x5 => TransportError::StreamStateError,
+            0x6 => TransportError::FinalOffsetError,
+            0x7 => TransportError::FrameEncodingError,
+            0x8 => TransportError::TransportParameterError,
+            0x9 => TransportError::VersionNegotiationError,
+            0xa => TransportError::ProtocolViolation,
+            0xc => TransportError::InvalidMigration,
+
+            0x100..=0x1ff => TransportError::CryptoError(code as u8),
+
+            _ => TransportError::Unknown(code),
+        }
+    }
+
+    pub fn to_code(self) -> u16 {
+        match self {
+            TransportError::NoError => 0x0,
+            TransportError::InternalError => 0x1,
+            TransportError::ServerBusy => 0x2,
+            TransportError::FlowControlError => 0x3,
+            TransportError::StreamIdError => 0x4,
+            TransportError::StreamStateError => 0x5,
+            TransportError::FinalOffsetError => 0x6,
+            TransportError::FrameEncodingError => 0x7,
+            TransportError::TransportParameterError => 0x8,
+            TransportError::VersionNegotiationError => 0x9,
+            TransportError::ProtocolViolation => 0xa,
+            TransportError::InvalidMigration => 0xc,
+
+            TransportError::CryptoError(alert) => 0x100 | u16::from(alert),
+
+            TransportError::Unknown(code) => code,
+        }
+    }
+}
+
+// Frame types as encoded on the wire.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum FrameType {
     Padding,
+    ResetStream,
+    ConnectionClose,
+    ApplicationClose,
+    MaxData,
+    MaxStreamData,
+    MaxStreamId,
+    Ping,
+    DataBlocked,
+    StreamDataBlocked,
+    StreamIdBlocked,
+    NewConnectionId,
+    StopSending,
+    Ack,
+    PathChallenge,
+    PathResponse,
+
+    // The low 3 bits are the OFF, LEN and FIN flags.
+    Stream(u8),
+
+    Crypto,
+    NewToken,
+    AckEcn,
+    HandshakeDone,
+
+    // The low bit is the LEN flag.
+    Datagram(u8),
+
+    // One of the registered length-prefixed extension types.
+    Extension(u64),
+}
+
+impl TryFrom<u64> for FrameType {
+    type Error = Error;
+
+    fn try_from(v: u64) -> Result<FrameType> {
+        let ty = match v {
+            0x00 => FrameType::Padding,
+            0x01 => FrameType::ResetStream,
+            0x02 => FrameType::ConnectionClose,
+            0x03 => FrameType::ApplicationClose,
+            0x04 => FrameType::MaxData,
+            0x05 => FrameType::MaxStreamData,
+            0x06 => FrameType::MaxStreamId,
+            0x07 => FrameType::Ping,
+            0x08 => FrameType::DataBlocked,
+            0x09 => FrameType::StreamDataBlocked,
+            0x0a => FrameType::StreamIdBlocked,
+            0x0b => FrameType::NewConnectionId,
+            0x0c => FrameType::StopSending,
+            0x0d => FrameType::Ack,
+            0x0e => FrameType::PathChallenge,
+            0x0f => FrameType::PathResponse,
+
+            0x10..=0x17 => FrameType::Stream(v as u8 & 0x07),
+
+            0x18 => FrameType::Crypto,
+            0x19 => FrameType::NewToken,
+            0x1a => FrameType::AckEcn,
+            0x1e => FrameType::HandshakeDone,
+
+            0x30 | 0x31 => FrameType::Datagram(v as u8 & 0x01),
+
+            _ if LENGTH_PREFIXED_EXT_TYPES.contains(&v) =>
+                FrameType::Extension(v),
+
+            _ => return Err(Error::UnknownFrame(v)),
+        };
+
+        Ok(ty)
+    }
+}
+
+impl From<FrameType> for u64 {
+    fn from(ty: FrameType) -> u64 {
+        match ty {
+            FrameType::Padding => 0x00,
+            FrameType::ResetStream => 0x01,
+            FrameType::ConnectionClose => 0x02,
+            FrameType::ApplicationClose => 0x03,
+            FrameType::MaxData => 0x04,
+            FrameType::MaxStreamData => 0x05,
+            FrameType::MaxStreamId => 0x06,
+            FrameType::Ping => 0x07,
+            FrameType::DataBlocked => 0x08,
+            FrameType::StreamDataBlocked => 0x09,
+            FrameType::StreamIdBlocked => 0x0a,
+            FrameType::NewConnectionId => 0x0b,
+            FrameType::StopSending => 0x0c,
+            FrameType::Ack => 0x0d,
+            FrameType::PathChallenge => 0x0e,
+            FrameType::PathResponse => 0x0f,
+
+            FrameType::Stream(flags) => 0x10 | u64::from(flags & 0x07),
+
+            FrameType::Crypto => 0x18,
+            FrameType::NewToken => 0x19,
+            FrameType::AckEcn => 0x1a,
+            FrameType::HandshakeDone => 0x1e,
+
+            FrameType::Datagram(flags) => 0x30 | u64::from(flags & 0x01),
+
+            FrameType::Extension(v) => v,
+        }
+    }
+}
+
+// ECN counters reported by an ACK_ECN frame.
+#[derive(PartialEq, Copy, Clone, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EcnCounts {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+// Cloning a parsed frame keeps borrowing the same packet buffer. Use
+// `into_owned()` to get a copy that can outlive it, e.g. for retransmission.
+//
+// With the "serde" feature, frames can also be serialized, e.g. to dump them
+// as JSON. Byte payloads are represented as hex strings.
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Frame<'a> {
+    Padding {
+        len: usize,
+    },
+
+    ResetStream {
+        stream_id: u64,
+        error_code: u16,
+        final_size: u64,
+    },
 
     ConnectionClose {
         error_code: u16,
         frame_type: u64,
-        reason: Vec<u8>,
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        reason: Cow<'a, [u8]>,
     },
 
     ApplicationClose {
         error_code: u16,
-        reason: Vec<u8>,
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        reason: Cow<'a, [u8]>,
+    },
+
+    MaxData {
+        max: u64,
+    },
+
+    MaxStreamData {
+        stream_id: u64,
+        max: u64,
+    },
+
+    MaxStreamId {
+        max: u64,
     },
 
     Ping,
 
+    DataBlocked {
+        limit: u64,
+    },
+
+    StreamDataBlocked {
+        stream_id: u64,
+        limit: u64,
+    },
+
+    StreamIdBlocked {
+        limit: u64,
+    },
+
     NewConnectionId {
         seq_num: u64,
-        conn_id: Vec<u8>,
-        reset_token: Vec<u8>,
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        conn_id: Cow<'a, [u8]>,
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        reset_token: Cow<'a, [u8]>,
+    },
+
+    StopSending {
+        stream_id: u64,
+        error_code: u16,
     },
 
     ACK {
-        largest_ack: u64,
+        // Raw value as encoded on the wire, not scaled by the peer's ACK
+        // delay exponent. See `ack_delay_micros()`.
         ack_delay: u64,
+        ranges: ranges::RangeSet,
+        ecn: Option<EcnCounts>,
+    },
+
+    PathChallenge {
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        data: [u8; 8],
+    },
+
+    PathResponse {
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        data: [u8; 8],
     },
 
     Crypto {
         offset: u64,
-        data: octets::Bytes<'a>,
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        data: Cow<'a, [u8]>,
     },
 
     Stream {
         stream_id: u64,
         offset: u64,
-        data: octets::Bytes<'a>,
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        data: Cow<'a, [u8]>,
         fin: bool,
     },
+
+    NewToken {
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        token: Cow<'a, [u8]>,
+    },
+
+    HandshakeDone,
+
+    Datagram {
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        data: Cow<'a, [u8]>,
+    },
+
+    UnknownExt {
+        frame_type: u64,
+        #[cfg_attr(feature = "serde", serde(with = "hex"))]
+        raw: Vec<u8>,
+    },
+}
+
+// Frames built for sending can't borrow from a packet buffer, so they own
+// their data instead.
+pub type FrameOwned = Frame<'static>;
+
+// When parsing a sequence of frames, errors also carry the offset in the
+// buffer of the frame that failed to parse.
+pub type ParseResult<T> = ::core::result::Result<T, (Error, usize)>;
+
+impl FrameOwned {
+    // Parses all the frames in the buffer, e.g. a decrypted packet payload.
+    // Frames are copied out of the buffer, so they can all be kept around
+    // at the same time.
+    pub fn parse_all(b: &mut octets::Bytes) -> ParseResult<Vec<FrameOwned>> {
+        let mut frames = Vec::new();
+
+        while b.cap() > 0 {
+            let off = b.off();
+
+            let frame = Frame::from_bytes(b).map_err(|e| (e, off))?;
+
+            frames.push(frame.into_owned());
+        }
+
+        Ok(frames)
+    }
+
+    pub fn ping() -> FrameOwned {
+        Frame::Ping
+    }
+
+    pub fn padding(len: usize) -> FrameOwned {
+        Frame::Padding { len }
+    }
+
+    pub fn stream(stream_id: u64, offset: u64, data: Vec<u8>, fin: bool)
+                                                            -> FrameOwned {
+        Frame::Stream {
+            stream_id,
+            offset,
+            data: Cow::Owned(data),
+            fin,
+        }
+    }
+
+    // Builds an ACK frame for a delay in microseconds, scaling it down with
+    // the ACK delay exponent advertised in our transport parameters.
+    pub fn ack(ranges: ranges::RangeSet, delay_micros: u64, exponent: u8)
+                                                            -> FrameOwned {
+        Frame::ACK {
+            ack_delay: delay_micros >> cmp::min(exponent, 63),
+            ranges,
+            ecn: None,
+        }
+    }
+
+    // Builds an ACK frame acknowledging all the given packet numbers. Unlike
+    // `ack()`, the delay is the raw value to put on the wire.
+    pub fn ack_from_received(received: &BTreeSet<u64>, ack_delay: u64)
+                                                    -> Result<FrameOwned> {
+        let mut ranges = ranges::RangeSet::default();
+
+        for pn in received {
+            ranges.insert(*pn)?;
+        }
+
+        Ok(Frame::ACK {
+            ack_delay,
+            ranges,
+            ecn: None,
+        })
+    }
+
+    // Builds a CONNECTION_CLOSE frame, with the type of the frame that
+    // triggered the error if any.
+    pub fn connection_close(error: TransportError,
+                            triggered_by: Option<FrameType>, reason: Vec<u8>)
+                                                            -> FrameOwned {
+        let frame_type = match triggered_by {
+            Some(ty) => ty.into(),
+            None => 0,
+        };
+
+        Frame::ConnectionClose {
+            error_code: error.to_code(),
+            frame_type,
+            reason: Cow::Owned(reason),
+        }
+    }
+
+    // Builds an extension frame to be forwarded verbatim. Only the types in
+    // LENGTH_PREFIXED_EXT_TYPES are accepted, as any other type would be
+    // encoded as a different frame, or not at all.
+    pub fn unknown_ext(frame_type: u64, raw: Vec<u8>) -> Result<FrameOwned> {
+        if !LENGTH_PREFIXED_EXT_TYPES.contains(&frame_type) {
+            return Err(Error::UnknownFrame(frame_type));
+        }
+
+        Ok(Frame::UnknownExt { frame_type, raw })
+    }
 }
 
 impl<'a> Frame<'a> {
     pub fn from_bytes(b: &'a mut octets::Bytes) -> Result<Frame<'a>> {
-        let frame_type = b.get_varint()?;
+        Frame::decode(b, false)
+    }
+
+    // Like `from_bytes()`, but fails with `Error::InvalidVarint` if any of
+    // the varints in the frame isn't minimally encoded.
+    pub fn from_bytes_strict(b: &'a mut octets::Bytes) -> Result<Frame<'a>> {
+        Frame::decode(b, true)
+    }
+
+    fn decode(b: &'a mut octets::Bytes, strict: bool) -> Result<Frame<'a>> {
+        let start = b.off();
 
-        // println!("GOT FRAME {:x}", frame_type);
+        let frame_type = FrameType::try_from(get_varint(b, strict)?)?;
 
         let frame = match frame_type {
-            0x00 => Frame::Padding,
+            FrameType::Padding => {
+                // Consume the whole run of PADDING as a single frame.
+                while b.cap() > 0 && b.peek_u8()? == 0x00 {
+                    b.skip(1)?;
+                }
+
+                Frame::Padding { len: b.off() - start }
+            },
+
+            FrameType::ResetStream => {
+                Frame::ResetStream {
+                    stream_id: get_varint(b, strict)?,
+                    error_code: b.get_u16()?,
+                    final_size: get_varint(b, strict)?,
+                }
+            },
 
-            0x02 => {
+            FrameType::ConnectionClose => {
                 Frame::ConnectionClose {
                     error_code: b.get_u16()?,
-                    frame_type: b.get_varint()?,
-                    reason: b.get_bytes_with_varint_length()?.to_vec(),
+                    frame_type: get_varint(b, strict)?,
+                    reason: Cow::Borrowed(
+                        get_bytes_with_varint_length(b, strict)?.into_slice()
+                    ),
                 }
             },
 
-            0x03 => {
+            FrameType::ApplicationClose => {
                 Frame::ApplicationClose {
                     error_code: b.get_u16()?,
-                    reason: b.get_bytes_with_varint_length()?.to_vec(),
+                    reason: Cow::Borrowed(
+                        get_bytes_with_varint_length(b, strict)?.into_slice()
+                    ),
                 }
             },
 
-            0x07 => Frame::Ping,
+            FrameType::MaxData => {
+                Frame::MaxData {
+                    max: get_varint(b, strict)?,
+                }
+            },
 
-            0x0b => {
-                Frame::NewConnectionId {
-                    seq_num: b.get_varint()?,
-                    conn_id: b.get_bytes_with_u8_length()?.to_vec(),
-                    reset_token: b.get_bytes(16)?.to_vec(),
+            FrameType::MaxStreamData => {
+                Frame::MaxStreamData {
+                    stream_id: get_varint(b, strict)?,
+                    max: get_varint(b, strict)?,
                 }
-            }
+            },
+
+            FrameType::MaxStreamId => {
+                Frame::MaxStreamId {
+                    max: get_varint(b, strict)?,
+                }
+            },
+
+            FrameType::Ping => Frame::Ping,
+
+            FrameType::DataBlocked => {
+                Frame::DataBlocked {
+                    limit: get_varint(b, strict)?,
+                }
+            },
+
+            FrameType::StreamDataBlocked => {
+                Frame::StreamDataBlocked {
+                    stream_id: get_varint(b, strict)?,
+                    limit: get_varint(b, strict)?,
+                }
+            },
+
+            FrameType::StreamIdBlocked => {
+                Frame::StreamIdBlocked {
+                    limit: get_varint(b, strict)?,
+                }
+            },
+
+            FrameType::NewConnectionId =>
+                parse_new_connection_id_frame(b, strict)?,
+
+            FrameType::StopSending => {
+                Frame::StopSending {
+                    stream_id: get_varint(b, strict)?,
+                    error_code: b.get_u16()?,
+                }
+            },
+
+            FrameType::Ack => parse_ack_frame(false, b, strict)?,
+
+            FrameType::PathChallenge => {
+                Frame::PathChallenge {
+                    data: parse_path_data(b)?,
+                }
+            },
 
-            0x0d => parse_ack_frame(frame_type, b)?,
+            FrameType::PathResponse => {
+                Frame::PathResponse {
+                    data: parse_path_data(b)?,
+                }
+            },
 
-            0x18 => {
+            FrameType::Stream(flags) => parse_stream_frame(flags, b, strict)?,
+
+            FrameType::Crypto => {
                 Frame::Crypto {
-                    offset: b.get_varint()?,
-                    data: b.get_bytes_with_varint_length()?,
+                    offset: get_varint(b, strict)?,
+                    data: Cow::Borrowed(get_bytes_with_varint_length(b, strict)?
+                                         .into_slice()),
+                }
+            },
+
+            FrameType::NewToken => {
+                let token = get_bytes_with_varint_length(b, strict)?;
+
+                if token.cap() == 0 {
+                    return Err(Error::InvalidToken);
                 }
-            }
 
-            0x10 => parse_stream_frame(frame_type, b)?,
-            0x11 => parse_stream_frame(frame_type, b)?,
-            0x12 => parse_stream_frame(frame_type, b)?,
-            0x13 => parse_stream_frame(frame_type, b)?,
-            0x14 => parse_stream_frame(frame_type, b)?,
-            0x15 => parse_stream_frame(frame_type, b)?,
-            0x16 => parse_stream_frame(frame_type, b)?,
-            0x17 => parse_stream_frame(frame_type, b)?,
+                Frame::NewToken {
+                    token: Cow::Borrowed(token.into_slice()),
+                }
+            },
+
+            FrameType::AckEcn => parse_ack_frame(true, b, strict)?,
+
+            FrameType::HandshakeDone => Frame::HandshakeDone,
 
-            _    => return Err(Error::UnknownFrame),
+            FrameType::Datagram(flags) =>
+                parse_datagram_frame(flags, b, strict)?,
+
+            FrameType::Extension(frame_type) => {
+                Frame::UnknownExt {
+                    frame_type,
+                    raw: get_bytes_with_varint_length(b, strict)?.to_vec(),
+                }
+            },
         };
 
+        validate_frame(&frame)?;
+
         Ok(frame)
     }
 
     pub fn to_bytes(&self, b: &mut octets::Bytes) -> Result<usize> {
+        // The variants' fields are public, so these can't be enforced when
+        // the frame is built.
+        match self {
+            Frame::UnknownExt { frame_type, .. }
+                if !LENGTH_PREFIXED_EXT_TYPES.contains(frame_type) =>
+                    return Err(Error::UnknownFrame(*frame_type)),
+
+            // There is no encoding for an ACK frame without any range.
+            Frame::ACK { ranges, .. } if ranges.is_empty() =>
+                return Err(Error::InvalidFrame),
+
+            _ => (),
+        }
+
+        // Fail before writing anything, so that a frame that doesn't fit
+        // doesn't leave a partial encoding behind.
+        if b.cap() < self.wire_len() {
+            return Err(Error::BufferTooShort);
+        }
+
         let before = b.cap();
 
         match self {
-            Frame::Padding => {
-                b.put_varint(0x00)?;
+            Frame::Padding { len } => {
+                for _ in 0..*len {
+                    b.put_varint(FrameType::Padding.into())?;
+                }
+            },
+
+            Frame::ResetStream { stream_id, error_code, final_size } => {
+                b.put_varint(FrameType::ResetStream.into())?;
+
+                b.put_varint(*stream_id)?;
+                b.put_u16(*error_code)?;
+                b.put_varint(*final_size)?;
 
                 ()
             },
 
             Frame::ConnectionClose { error_code, frame_type, reason } => {
-                b.put_varint(0x02)?;
+                b.put_varint(FrameType::ConnectionClose.into())?;
 
                 b.put_u16(*error_code)?;
                 b.put_varint(*frame_type)?;
-                b.put_varint(reason.len() as u64)?;
-                b.put_bytes(reason.as_ref())?;
+                b.put_bytes_with_varint_length(reason)?;
 
                 ()
             },
 
             Frame::ApplicationClose { error_code, reason } => {
-                b.put_varint(0x03)?;
+                b.put_varint(FrameType::ApplicationClose.into())?;
 
                 b.put_u16(*error_code)?;
-                b.put_varint(reason.len() as u64)?;
-                b.put_bytes(reason.as_ref())?;
+                b.put_bytes_with_varint_length(reason)?;
+
+                ()
+            },
+
+            Frame::MaxData { max } => {
+                b.put_varint(FrameType::MaxData.into())?;
+
+                b.put_varint(*max)?;
+
+                ()
+            },
+
+            Frame::MaxStreamData { stream_id, max } => {
+                b.put_varint(FrameType::MaxStreamData.into())?;
+
+                b.put_varint(*stream_id)?;
+                b.put_varint(*max)?;
+
+                ()
+            },
+
+            Frame::MaxStreamId { max } => {
+                b.put_varint(FrameType::MaxStreamId.into())?;
+
+                b.put_varint(*max)?;
 
                 ()
             },
 
             Frame::Ping => {
-                b.put_varint(0x07)?;
+                b.put_varint(FrameType::Ping.into())?;
+
+                ()
+            },
+
+            Frame::DataBlocked { limit } => {
+                b.put_varint(FrameType::DataBlocked.into())?;
+
+                b.put_varint(*limit)?;
+
+                ()
+            },
+
+            Frame::StreamDataBlocked { stream_id, limit } => {
+                b.put_varint(FrameType::StreamDataBlocked.into())?;
+
+                b.put_varint(*stream_id)?;
+                b.put_varint(*limit)?;
+
+                ()
+            },
+
+            Frame::StreamIdBlocked { limit } => {
+                b.put_varint(FrameType::StreamIdBlocked.into())?;
+
+                b.put_varint(*limit)?;
 
                 ()
             },
 
             Frame::NewConnectionId { seq_num, conn_id, reset_token } => {
-                b.put_varint(0x0b)?;
+                b.put_varint(FrameType::NewConnectionId.into())?;
 
                 b.put_varint(*seq_num)?;
                 b.put_u8(conn_id.len() as u8)?;
@@ -176,64 +744,645 @@ impl<'a> Frame<'a> {
                 ()
             }
 
-            Frame::ACK { largest_ack, ack_delay } => {
-                b.put_varint(0x0d)?;
+            Frame::StopSending { stream_id, error_code } => {
+                b.put_varint(FrameType::StopSending.into())?;
 
-                b.put_varint(*largest_ack)?;
-                b.put_varint(*ack_delay)?;
-                b.put_varint(0)?;
-                b.put_varint(0)?;
+                b.put_varint(*stream_id)?;
+                b.put_u16(*error_code)?;
 
                 ()
             },
 
-            Frame::Crypto { offset, data } => {
-                b.put_varint(0x18)?;
+            Frame::ACK { ack_delay, ranges, ecn } => {
+                b.put_varint(self.ty().into())?;
 
-                b.put_varint(*offset)?;
-                b.put_varint(data.cap() as u64)?;
-                b.put_bytes(data.as_ref())?;
+                // Blocks are encoded starting from the largest range.
+                let mut it = ranges.iter().rev();
 
-                ()
-            }
+                let first = match it.next() {
+                    Some(r) => r,
 
-            Frame::Stream { stream_id, offset, data, fin } => {
-                let mut ty: u8 = 0x10;
+                    None => return Err(Error::InvalidFrame),
+                };
 
-                // Always encode offset
-                ty |= 0x04;
+                let largest_ack = first.end - 1;
+                let block_count = ranges.len() as u64 - 1;
+                let first_block = first.end - first.start - 1;
 
-                // Always encode length
-                ty |= 0x02;
+                b.put_varint(largest_ack)?;
+                b.put_varint(*ack_delay)?;
+                b.put_varint(block_count)?;
+                b.put_varint(first_block)?;
 
-                if *fin {
-                    ty |= 0x01;
-                }
+                let mut smallest = first.start;
 
-                b.put_varint(u64::from(ty))?;
+                for r in it {
+                    let gap = smallest - r.end - 1;
+                    let ack_block = r.end - r.start - 1;
 
-                b.put_varint(*stream_id)?;
-                b.put_varint(*offset)?;
-                b.put_varint(data.cap() as u64)?;
-                b.put_bytes(data.as_ref())?;
+                    b.put_varint(gap)?;
+                    b.put_varint(ack_block)?;
+
+                    smallest = r.start;
+                }
+
+                if let Some(ecn) = ecn {
+                    b.put_varint(ecn.ect0)?;
+                    b.put_varint(ecn.ect1)?;
+                    b.put_varint(ecn.ce)?;
+                }
 
                 ()
-            }
-        }
+            },
 
-        Ok(before - b.cap())
-    }
+            Frame::PathChallenge { data } => {
+                b.put_varint(FrameType::PathChallenge.into())?;
 
-    pub fn wire_len(&self) -> usize {
-        match self {
-            Frame::Padding => 1, // type
+                b.put_bytes(data)?;
 
-            Frame::ConnectionClose { frame_type, reason, .. } => {
-                1 +                                // frame type
-                2 +                                // error_code
-                octets::varint_len(*frame_type) +  // frame_type
-                octets::varint_len(reason.len() as u64) + // reason_len
-                reason.len()                       // reason
+                ()
+            },
+
+            Frame::PathResponse { data } => {
+                b.put_varint(FrameType::PathResponse.into())?;
+
+                b.put_bytes(data)?;
+
+                ()
+            },
+
+            Frame::Crypto { offset, data } => {
+                b.put_varint(FrameType::Crypto.into())?;
+
+                b.put_varint(*offset)?;
+                b.put_bytes_with_varint_length(data)?;
+
+                ()
+            }
+
+            Frame::Stream { stream_id, offset, data, .. } => {
+                b.put_varint(self.ty().into())?;
+
+                b.put_varint(*stream_id)?;
+                b.put_varint(*offset)?;
+                b.put_bytes_with_varint_length(data)?;
+
+                ()
+            }
+
+            Frame::NewToken { token } => {
+                b.put_varint(FrameType::NewToken.into())?;
+
+                b.put_bytes_with_varint_length(token)?;
+
+                ()
+            },
+
+            Frame::HandshakeDone => {
+                b.put_varint(FrameType::HandshakeDone.into())?;
+
+                ()
+            },
+
+            Frame::Datagram { data } => {
+                // Always encode length
+                b.put_varint(FrameType::Datagram(0x01).into())?;
+
+                b.put_bytes_with_varint_length(data)?;
+
+                ()
+            },
+
+            Frame::UnknownExt { raw, .. } => {
+                b.put_varint(self.ty().into())?;
+
+                b.put_bytes_with_varint_length(raw)?;
+
+                ()
+            },
+        }
+
+        Ok(before - b.cap())
+    }
+
+    // Like `to_bytes()`, but encodes the length of STREAM frames using
+    // exactly `len_width` bytes, so that the header size doesn't depend on
+    // the amount of data. Other frames are encoded as usual.
+    pub fn to_bytes_with_stream_len_width(&self, b: &mut octets::Bytes,
+                                          len_width: usize) -> Result<usize> {
+        match self {
+            Frame::Stream { stream_id, offset, data, .. } => {
+                let data_len_width = octets::varint_len(data.len() as u64);
+
+                // Checked upfront, like the capacity, so that nothing is
+                // written when the length can't be encoded that way.
+                if !matches!(len_width, 1 | 2 | 4 | 8) ||
+                   len_width < data_len_width {
+                    return Err(Error::InvalidVarint);
+                }
+
+                let len = self.wire_len() - data_len_width + len_width;
+
+                if b.cap() < len {
+                    return Err(Error::BufferTooShort);
+                }
+
+                let before = b.cap();
+
+                b.put_varint(u64::from(self.first_byte()))?;
+
+                b.put_varint(*stream_id)?;
+                b.put_varint(*offset)?;
+                b.put_varint_with_len(data.len() as u64, len_width)?;
+                b.put_bytes(data.as_ref())?;
+
+                Ok(before - b.cap())
+            },
+
+            _ => self.to_bytes(b),
+        }
+    }
+
+    // Whether the frame's bytes count toward the congestion window. A packet
+    // containing only frames for which this is false (i.e. ACK and close
+    // frames) is not counted as in flight.
+    pub fn counts_toward_cwnd(&self) -> bool {
+        !matches!(self, Frame::ACK { .. } |
+                        Frame::ConnectionClose { .. } |
+                        Frame::ApplicationClose { .. })
+    }
+
+    // Whether receiving the frame requires sending an ACK. All variants are
+    // listed explicitly, so that new frames need to make a decision.
+    pub fn is_ack_eliciting(&self) -> bool {
+        match self {
+            Frame::Padding { .. } => false,
+            Frame::ResetStream { .. } => true,
+            Frame::ConnectionClose { .. } => false,
+            Frame::ApplicationClose { .. } => false,
+            Frame::MaxData { .. } => true,
+            Frame::MaxStreamData { .. } => true,
+            Frame::MaxStreamId { .. } => true,
+            Frame::Ping => true,
+            Frame::DataBlocked { .. } => true,
+            Frame::StreamDataBlocked { .. } => true,
+            Frame::StreamIdBlocked { .. } => true,
+            Frame::NewConnectionId { .. } => true,
+            Frame::StopSending { .. } => true,
+            Frame::ACK { .. } => false,
+            Frame::PathChallenge { .. } => true,
+            Frame::PathResponse { .. } => true,
+            Frame::Crypto { .. } => true,
+            Frame::Stream { .. } => true,
+            Frame::NewToken { .. } => true,
+            Frame::HandshakeDone => true,
+            Frame::Datagram { .. } => true,
+            Frame::UnknownExt { .. } => true,
+        }
+    }
+
+    // Whether the frame needs to be queued again when the packet carrying it
+    // is declared lost.
+    pub fn is_retransmittable(&self) -> bool {
+        match self {
+            // Carry no information.
+            Frame::Padding { .. } => false,
+            Frame::Ping => false,
+
+            // Regenerated from the current state of received packets.
+            Frame::ACK { .. } => false,
+
+            // Only sent in response to the peer's packets while closing.
+            Frame::ConnectionClose { .. } => false,
+            Frame::ApplicationClose { .. } => false,
+
+            // Path validation uses fresh challenges, and a response is only
+            // valid for the packet it replied to.
+            Frame::PathChallenge { .. } => false,
+            Frame::PathResponse { .. } => false,
+
+            // Unreliable by definition.
+            Frame::Datagram { .. } => false,
+
+            // The meaning of the frame is unknown, so it can't be known
+            // whether resending would be correct.
+            Frame::UnknownExt { .. } => false,
+
+            // Stream and crypto data, and state the peer needs to learn
+            // eventually. Flow control and blocked frames could be replaced
+            // by a more recent value instead, but resending them is safe.
+            Frame::ResetStream { .. } => true,
+            Frame::MaxData { .. } => true,
+            Frame::MaxStreamData { .. } => true,
+            Frame::MaxStreamId { .. } => true,
+            Frame::DataBlocked { .. } => true,
+            Frame::StreamDataBlocked { .. } => true,
+            Frame::StreamIdBlocked { .. } => true,
+            Frame::NewConnectionId { .. } => true,
+            Frame::StopSending { .. } => true,
+            Frame::Crypto { .. } => true,
+            Frame::Stream { .. } => true,
+            Frame::NewToken { .. } => true,
+            Frame::HandshakeDone => true,
+        }
+    }
+
+    // Serializes the frame at the end of the given vector, growing it as
+    // needed.
+    pub fn append_to_vec(&self, v: &mut Vec<u8>) -> Result<usize> {
+        let off = v.len();
+
+        v.resize(off + self.wire_len(), 0);
+
+        let written = {
+            let mut b = octets::Bytes::new(&mut v[off..]);
+            self.to_bytes(&mut b)
+        };
+
+        match written {
+            Ok(len) => {
+                v.truncate(off + len);
+                Ok(len)
+            },
+
+            Err(e) => {
+                v.truncate(off);
+                Err(e)
+            },
+        }
+    }
+
+    // Copies any borrowed data, so the frame can outlive the buffer it was
+    // parsed from.
+    pub fn into_owned(self) -> FrameOwned {
+        match self {
+            Frame::Padding { len } => Frame::Padding { len },
+
+            Frame::ResetStream { stream_id, error_code, final_size } =>
+                Frame::ResetStream { stream_id, error_code, final_size },
+
+            Frame::ConnectionClose { error_code, frame_type, reason } =>
+                Frame::ConnectionClose {
+                    error_code,
+                    frame_type,
+                    reason: Cow::Owned(reason.into_owned()),
+                },
+
+            Frame::ApplicationClose { error_code, reason } =>
+                Frame::ApplicationClose {
+                    error_code,
+                    reason: Cow::Owned(reason.into_owned()),
+                },
+
+            Frame::MaxData { max } => Frame::MaxData { max },
+
+            Frame::MaxStreamData { stream_id, max } =>
+                Frame::MaxStreamData { stream_id, max },
+
+            Frame::MaxStreamId { max } => Frame::MaxStreamId { max },
+
+            Frame::Ping => Frame::Ping,
+
+            Frame::DataBlocked { limit } => Frame::DataBlocked { limit },
+
+            Frame::StreamDataBlocked { stream_id, limit } =>
+                Frame::StreamDataBlocked { stream_id, limit },
+
+            Frame::StreamIdBlocked { limit } =>
+                Frame::StreamIdBlocked { limit },
+
+            Frame::NewConnectionId { seq_num, conn_id, reset_token } =>
+                Frame::NewConnectionId {
+                    seq_num,
+                    conn_id: Cow::Owned(conn_id.into_owned()),
+                    reset_token: Cow::Owned(reset_token.into_owned()),
+                },
+
+            Frame::StopSending { stream_id, error_code } =>
+                Frame::StopSending { stream_id, error_code },
+
+            Frame::ACK { ack_delay, ranges, ecn } =>
+                Frame::ACK { ack_delay, ranges, ecn },
+
+            Frame::PathChallenge { data } => Frame::PathChallenge { data },
+
+            Frame::PathResponse { data } => Frame::PathResponse { data },
+
+            Frame::Crypto { offset, data } =>
+                Frame::Crypto {
+                    offset,
+                    data: Cow::Owned(data.into_owned()),
+                },
+
+            Frame::Stream { stream_id, offset, data, fin } =>
+                Frame::Stream {
+                    stream_id,
+                    offset,
+                    data: Cow::Owned(data.into_owned()),
+                    fin,
+                },
+
+            Frame::NewToken { token } =>
+                Frame::NewToken {
+                    token: Cow::Owned(token.into_owned()),
+                },
+
+            Frame::HandshakeDone => Frame::HandshakeDone,
+
+            Frame::Datagram { data } =>
+                Frame::Datagram {
+                    data: Cow::Owned(data.into_owned()),
+                },
+
+            Frame::UnknownExt { frame_type, raw } =>
+                Frame::UnknownExt { frame_type, raw },
+        }
+    }
+
+    pub fn ty(&self) -> FrameType {
+        match self {
+            Frame::Padding { .. } => FrameType::Padding,
+
+            Frame::ResetStream { .. } => FrameType::ResetStream,
+
+            Frame::ConnectionClose { .. } => FrameType::ConnectionClose,
+
+            Frame::ApplicationClose { .. } => FrameType::ApplicationClose,
+
+            Frame::MaxData { .. } => FrameType::MaxData,
+
+            Frame::MaxStreamData { .. } => FrameType::MaxStreamData,
+
+            Frame::MaxStreamId { .. } => FrameType::MaxStreamId,
+
+            Frame::Ping => FrameType::Ping,
+
+            Frame::DataBlocked { .. } => FrameType::DataBlocked,
+
+            Frame::StreamDataBlocked { .. } => FrameType::StreamDataBlocked,
+
+            Frame::StreamIdBlocked { .. } => FrameType::StreamIdBlocked,
+
+            Frame::NewConnectionId { .. } => FrameType::NewConnectionId,
+
+            Frame::StopSending { .. } => FrameType::StopSending,
+
+            Frame::ACK { ecn: None, .. } => FrameType::Ack,
+
+            Frame::ACK { ecn: Some(_), .. } => FrameType::AckEcn,
+
+            Frame::PathChallenge { .. } => FrameType::PathChallenge,
+
+            Frame::PathResponse { .. } => FrameType::PathResponse,
+
+            Frame::Crypto { .. } => FrameType::Crypto,
+
+            Frame::Stream { fin, .. } => {
+                let mut flags: u8 = 0;
+
+                // Always encode offset
+                flags |= 0x04;
+
+                // Always encode length
+                flags |= 0x02;
+
+                if *fin {
+                    flags |= 0x01;
+                }
+
+                FrameType::Stream(flags)
+            },
+
+            Frame::NewToken { .. } => FrameType::NewToken,
+
+            Frame::HandshakeDone => FrameType::HandshakeDone,
+
+            // Always encode length
+            Frame::Datagram { .. } => FrameType::Datagram(0x01),
+
+            Frame::UnknownExt { frame_type, .. } =>
+                FrameType::Extension(*frame_type),
+        }
+    }
+
+    // Returns the first byte of the frame type's varint encoding. A type too
+    // large to be encoded, which only an UnknownExt frame that to_bytes()
+    // would reject can have, is treated as the largest varint.
+    pub fn first_byte(&self) -> u8 {
+        let ty = cmp::min(self.frame_type(), octets::max_varint());
+
+        let len = octets::varint_len(ty);
+        let top = (ty >> ((len - 1) * 8)) as u8;
+
+        match len {
+            1 => top,
+            2 => top | 0x40,
+            4 => top | 0x80,
+            _ => top | 0xc0,
+        }
+    }
+
+    pub fn frame_type(&self) -> u64 {
+        self.ty().into()
+    }
+
+    // Returns the ACK delay in microseconds, scaled by the peer's ACK delay
+    // exponent. This is 0 for frames other than ACK.
+    pub fn ack_delay_micros(&self, exponent: u8) -> u64 {
+        match self {
+            Frame::ACK { ack_delay, .. } =>
+                ack_delay.saturating_mul(1 << cmp::min(exponent, 63)),
+
+            _ => 0,
+        }
+    }
+
+    // Returns the error code of a CONNECTION_CLOSE frame. The codes of
+    // APPLICATION_CLOSE frames are application-specific, so they are not
+    // mapped.
+    pub fn transport_error(&self) -> Option<TransportError> {
+        match self {
+            Frame::ConnectionClose { error_code, .. } =>
+                Some(TransportError::from_code(*error_code)),
+
+            _ => None,
+        }
+    }
+
+    // Returns the reason of a close frame as text, replacing invalid UTF-8
+    // sequences, e.g. for logging.
+    pub fn reason_str(&self) -> Option<Cow<str>> {
+        match self {
+            Frame::ConnectionClose { reason, .. } =>
+                Some(String::from_utf8_lossy(reason)),
+
+            Frame::ApplicationClose { reason, .. } =>
+                Some(String::from_utf8_lossy(reason)),
+
+            _ => None,
+        }
+    }
+
+    // Returns the largest packet number acknowledged by an ACK frame. This
+    // is only a usable RTT sample if the packet wasn't already acknowledged
+    // by a previous ACK, which the caller needs to check against its own
+    // record of acknowledged packets.
+    pub fn largest_acked(&self) -> Option<u64> {
+        match self {
+            Frame::ACK { ranges, .. } => ranges.largest(),
+
+            _ => None,
+        }
+    }
+
+    // Iterates over the packet numbers acknowledged by an ACK frame, from
+    // the largest to the smallest. Nothing is returned for other frames.
+    pub fn acked_packets<'b>(&'b self) -> impl Iterator<Item = u64> + 'b {
+        let ranges = match self {
+            Frame::ACK { ranges, .. } => Some(ranges),
+
+            _ => None,
+        };
+
+        ranges.into_iter()
+              .flat_map(|ranges| ranges.iter().rev())
+              .flat_map(|r| r.clone().rev())
+    }
+
+    // Returns the range of CRYPTO stream offsets covered by a CRYPTO frame,
+    // as (offset, end). CRYPTO data is not subject to flow control, so the
+    // amount of it buffered needs a separate limit (see CryptoBuffer).
+    pub fn crypto_range(&self) -> Option<(u64, u64)> {
+        match self {
+            Frame::Crypto { offset, data } =>
+                Some((*offset, *offset + data.len() as u64)),
+
+            _ => None,
+        }
+    }
+
+    // Returns the range of stream offsets covered by a STREAM frame, along
+    // with whether it carries the FIN bit, as (offset, end, fin).
+    pub fn stream_range(&self) -> Option<(u64, u64, bool)> {
+        match self {
+            Frame::Stream { offset, data, fin, .. } =>
+                Some((*offset, *offset + data.len() as u64, *fin)),
+
+            _ => None,
+        }
+    }
+
+    // Returns the data carried by a STREAM frame, which starts at the
+    // offset returned by `stream_range()`.
+    pub fn stream_data(&self) -> Option<&[u8]> {
+        match self {
+            Frame::Stream { data, .. } => Some(data.as_ref()),
+
+            _ => None,
+        }
+    }
+
+    // Returns the number of bytes the frame counts against flow control
+    // limits, i.e. the length of the data it carries, as opposed to
+    // `wire_len()` which also includes the frame header. This is 0 for
+    // all frames other than STREAM and DATAGRAM.
+    pub fn flow_control_len(&self) -> u64 {
+        match self {
+            Frame::Stream { data, .. } => data.len() as u64,
+
+            Frame::Datagram { data } => data.len() as u64,
+
+            _ => 0,
+        }
+    }
+
+    pub fn fits_in(&self, cap: usize) -> bool {
+        self.wire_len() <= cap
+    }
+
+    // Returns a copy of a STREAM or CRYPTO frame carrying only as much of
+    // the data as fits in `cap` bytes, or None if not even the header does.
+    // The data is borrowed from `self`. A truncated STREAM frame doesn't
+    // carry the FIN bit.
+    //
+    // DATAGRAM frames can't be split, so they're only returned if they fit
+    // as a whole. None is returned for all other frames.
+    pub fn truncated_to(&self, cap: usize) -> Option<Frame> {
+        let data = match self {
+            Frame::Crypto { data, .. } => data,
+            Frame::Stream { data, .. } => data,
+
+            Frame::Datagram { data } => {
+                if !self.fits_in(cap) {
+                    return None;
+                }
+
+                return Some(Frame::Datagram {
+                    data: Cow::Borrowed(data.as_ref()),
+                });
+            },
+
+            _ => return None,
+        };
+
+        // The size of the frame without the data and its length.
+        let header_len = self.wire_len() -
+                         octets::varint_len(data.len() as u64) - data.len();
+
+        if header_len + 1 > cap {
+            return None;
+        }
+
+        let avail = cap - header_len;
+
+        let mut len = cmp::min(data.len(), avail - 1);
+
+        while octets::varint_len(len as u64) + len > avail {
+            len -= 1;
+        }
+
+        let frame = match self {
+            Frame::Crypto { offset, .. } => Frame::Crypto {
+                offset: *offset,
+                data: Cow::Borrowed(&data[..len]),
+            },
+
+            Frame::Stream { stream_id, offset, fin, .. } => Frame::Stream {
+                stream_id: *stream_id,
+                offset: *offset,
+                data: Cow::Borrowed(&data[..len]),
+                fin: *fin && len == data.len(),
+            },
+
+            _ => unreachable!(),
+        };
+
+        Some(frame)
+    }
+
+    // Whether the frame's data counts toward stream and connection flow
+    // control limits.
+    pub fn is_flow_controlled(&self) -> bool {
+        matches!(self, Frame::Stream { .. })
+    }
+
+    pub fn wire_len(&self) -> usize {
+        match self {
+            Frame::Padding { len } => *len,
+
+            Frame::ResetStream { stream_id, final_size, .. } => {
+                1 +                                // frame type
+                octets::varint_len(*stream_id) +   // stream_id
+                2 +                                // error_code
+                octets::varint_len(*final_size)    // final_size
+            },
+
+            Frame::ConnectionClose { frame_type, reason, .. } => {
+                1 +                                // frame type
+                2 +                                // error_code
+                octets::varint_len(*frame_type) +  // frame_type
+                octets::varint_len(reason.len() as u64) + // reason_len
+                reason.len()                       // reason
             },
 
             Frame::ApplicationClose { reason, .. } => {
@@ -243,271 +1392,4104 @@ impl<'a> Frame<'a> {
                 reason.len()                       // reason
             },
 
-            Frame::Ping => 1, // type
+            Frame::MaxData { max } => {
+                1 +                                // frame type
+                octets::varint_len(*max)           // max
+            },
+
+            Frame::MaxStreamData { stream_id, max } => {
+                1 +                                // frame type
+                octets::varint_len(*stream_id) +   // stream_id
+                octets::varint_len(*max)           // max
+            },
+
+            Frame::MaxStreamId { max } => {
+                1 +                                // frame type
+                octets::varint_len(*max)           // max
+            },
+
+            Frame::Ping => 1, // type
+
+            Frame::DataBlocked { limit } => {
+                1 +                                // frame type
+                octets::varint_len(*limit)         // limit
+            },
+
+            Frame::StreamDataBlocked { stream_id, limit } => {
+                1 +                                // frame type
+                octets::varint_len(*stream_id) +   // stream_id
+                octets::varint_len(*limit)         // limit
+            },
+
+            Frame::StreamIdBlocked { limit } => {
+                1 +                                // frame type
+                octets::varint_len(*limit)         // limit
+            },
+
+            Frame::NewConnectionId { seq_num, conn_id, reset_token } => {
+                1 +                                // frame type
+                octets::varint_len(*seq_num) +     // seq_num
+                1 +                                // conn_id length
+                conn_id.len() +                    // conn_id
+                reset_token.len()                  // reset_token
+            },
+
+            Frame::StopSending { stream_id, .. } => {
+                1 +                                // frame type
+                octets::varint_len(*stream_id) +   // stream_id
+                2                                  // error_code
+            },
+
+            Frame::ACK { ack_delay, ranges, ecn } => {
+                let mut it = ranges.iter().rev();
+
+                let first = match it.next() {
+                    Some(r) => r,
+
+                    None => return 0,
+                };
+
+                let largest_ack = first.end - 1;
+                let block_count = ranges.len() as u64 - 1;
+                let first_block = first.end - first.start - 1;
+
+                let mut len = 1 +                              // frame type
+                              octets::varint_len(largest_ack) +  // largest_ack
+                              octets::varint_len(*ack_delay) +   // ack_delay
+                              octets::varint_len(block_count) +  // block_count
+                              octets::varint_len(first_block);   // first_block
+
+                let mut smallest = first.start;
+
+                for r in it {
+                    let gap = smallest - r.end - 1;
+                    let ack_block = r.end - r.start - 1;
+
+                    len += octets::varint_len(gap) +
+                           octets::varint_len(ack_block);
+
+                    smallest = r.start;
+                }
+
+                if let Some(ecn) = ecn {
+                    len += octets::varint_len(ecn.ect0) +
+                           octets::varint_len(ecn.ect1) +
+                           octets::varint_len(ecn.ce);
+                }
+
+                len
+            }
+
+            Frame::PathChallenge { .. } => {
+                1 +                                // frame type
+                8                                  // data
+            },
+
+            Frame::PathResponse { .. } => {
+                1 +                                // frame type
+                8                                  // data
+            },
+
+            Frame::Crypto { offset, data } => {
+                1 +                              // frame type
+                octets::varint_len(*offset) +    // offset
+                octets::varint_len(data.len() as u64) + // length
+                data.len()                       // data
+            }
+
+            Frame::Stream { stream_id, offset, data, .. } => {
+                1 +                              // frame type
+                octets::varint_len(*stream_id) + // stream_id
+                octets::varint_len(*offset) +    // offset
+                octets::varint_len(data.len() as u64) + // length
+                data.len()                       // data
+            }
+
+            Frame::NewToken { token } => {
+                1 +                                // frame type
+                octets::varint_len(token.len() as u64) + // token length
+                token.len()                        // token
+            },
+
+            Frame::HandshakeDone => 1, // type
+
+            Frame::Datagram { data } => {
+                1 +                                // frame type
+                octets::varint_len(data.len() as u64) + // length
+                data.len()                         // data
+            },
+
+            Frame::UnknownExt { frame_type, raw } => {
+                octets::varint_len(*frame_type) +      // frame type
+                octets::varint_len(raw.len() as u64) + // length
+                raw.len()                              // raw
+            },
+        }
+    }
+}
+
+// Parses the frames in a buffer one at a time, without copying STREAM and
+// CRYPTO data. Each frame borrows the underlying buffer until it's dropped,
+// so this can't implement Iterator, and frames need to be consumed before
+// the next one is parsed. After an error no more frames are returned.
+pub struct FrameCursor<'a, 'b: 'a> {
+    b: &'a mut octets::Bytes<'b>,
+    failed: bool,
+}
+
+impl<'a, 'b> FrameCursor<'a, 'b> {
+    pub fn new(b: &'a mut octets::Bytes<'b>) -> FrameCursor<'a, 'b> {
+        FrameCursor {
+            b,
+            failed: false,
+        }
+    }
+
+    pub fn next_frame(&mut self) -> Option<ParseResult<Frame>> {
+        if self.failed || self.b.cap() == 0 {
+            return None;
+        }
+
+        let off = self.b.off();
+
+        let frame = Frame::from_bytes(self.b).map_err(|e| (e, off));
+
+        if frame.is_err() {
+            self.failed = true;
+        }
+
+        Some(frame)
+    }
+}
+
+// Parses a whole packet payload. The payload must contain at least one
+// frame, and any bytes that don't decode as a frame make the whole payload
+// invalid.
+pub fn validate_payload(b: &mut octets::Bytes) -> Result<Vec<FrameOwned>> {
+    if b.cap() == 0 {
+        return Err(Error::InvalidPacket);
+    }
+
+    Frame::parse_all(b).map_err(|(e, _)| e)
+}
+
+// Parses as many frames as possible from arbitrary input, and checks that
+// each of them survives being encoded and parsed again. Parse errors simply
+// end the run; only an encoding that doesn't roundtrip panics.
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_roundtrip(input: &[u8]) {
+    let mut buf = input.to_vec();
+
+    let mut b = octets::Bytes::new(&mut buf);
+
+    while b.cap() > 0 {
+        let frame = match Frame::from_bytes(&mut b) {
+            Ok(v) => v,
+
+            Err(_) => return,
+        };
+
+        let mut out = Vec::new();
+
+        let wire_len = frame.append_to_vec(&mut out)
+                            .expect("parsed frame failed to encode");
+
+        assert_eq!(wire_len, frame.wire_len());
+
+        // Our own encoding uses minimal varints, so it must also pass the
+        // strict parser.
+        let mut b = octets::Bytes::new(&mut out);
+        let parsed = Frame::from_bytes_strict(&mut b)
+                           .expect("encoded frame failed to parse");
+
+        assert_eq!(parsed, frame);
+        assert_eq!(b.cap(), 0);
+    }
+}
+
+// Checks that an ACK frame doesn't acknowledge packets that were never sent,
+// given the highest packet number sent so far.
+pub fn validate_ack_against_sent(frame: &Frame, highest_sent: u64)
+                                                            -> Result<()> {
+    match frame {
+        Frame::ACK { ranges, .. } if ranges.largest() > Some(highest_sent) =>
+            Err(Error::InvalidFrame),
+
+        _ => Ok(()),
+    }
+}
+
+// Checks that a NEW_CONNECTION_ID frame's sequence number is larger than
+// `prev_max`, the largest one received so far (or 0, the sequence number of
+// the connection ID used during the handshake).
+pub fn validate_new_connection_id_seq(prev_max: u64, frame: &Frame)
+                                                            -> Result<()> {
+    match frame {
+        Frame::NewConnectionId { seq_num, .. } if *seq_num <= prev_max =>
+            Err(Error::InvalidFrame),
+
+        _ => Ok(()),
+    }
+}
+
+// Checks the ECN counts of an ACK_ECN frame against `prev`, the counts
+// reported by the previous one. The counts are cumulative, so they can't
+// decrease, and together they can't grow by more than `newly_acked`, the
+// number of packets the frame acknowledges for the first time.
+pub fn validate_ecn_counts(frame: &Frame, prev: &EcnCounts,
+                           newly_acked: u64) -> Result<()> {
+    let ecn = match frame {
+        Frame::ACK { ecn: Some(ecn), .. } => ecn,
+
+        _ => return Ok(()),
+    };
+
+    let increase = |cur: u64, prev: u64| {
+        cur.checked_sub(prev).ok_or(Error::InvalidFrame)
+    };
+
+    let total = increase(ecn.ect0, prev.ect0)? +
+                increase(ecn.ect1, prev.ect1)? +
+                increase(ecn.ce, prev.ce)?;
+
+    if total > newly_acked {
+        return Err(Error::InvalidFrame);
+    }
+
+    Ok(())
+}
+
+// Checks that a MAX_STREAM_DATA frame is for a stream the local endpoint can
+// send on. The second least significant bit of a stream ID is set for
+// unidirectional streams, and the least significant one for streams opened
+// by the server, so a unidirectional stream opened by the peer is
+// receive-only.
+pub fn validate_max_stream_data(frame: &Frame, is_server: bool)
+                                                            -> Result<()> {
+    match frame {
+        Frame::MaxStreamData { stream_id, .. }
+            if *stream_id & 0x2 != 0 &&
+               (*stream_id & 0x1 != 0) != is_server =>
+                Err(Error::InvalidStreamState),
+
+        _ => Ok(()),
+    }
+}
+
+// Returns the packets acknowledged by an ACK frame that aren't in `prev`,
+// e.g. the set of packets already known to be acknowledged. The result is
+// empty for other frames.
+pub fn newly_acked(prev: &ranges::RangeSet, ack: &Frame) -> ranges::RangeSet {
+    match ack {
+        Frame::ACK { ranges, .. } => ranges.difference(prev),
+
+        _ => ranges::RangeSet::default(),
+    }
+}
+
+// Writes an ACK frame for the given set of received packet numbers, using
+// at most `budget` bytes. Ranges are encoded starting from the largest one,
+// and the lowest ones are dropped if they don't fit. If `ecn` is given, an
+// ACK_ECN frame carrying the counters is written instead.
+//
+// Returns the number of bytes written and the lowest packet number that was
+// actually acknowledged.
+pub fn write_ack_capped(ranges: &ranges::RangeSet, ack_delay: u64,
+                        ecn: Option<EcnCounts>, b: &mut octets::Bytes,
+                        budget: usize) -> Result<(usize, u64)> {
+    let budget = cmp::min(budget, b.cap());
+
+    let mut iter = ranges.iter().rev();
+
+    let first = match iter.next() {
+        Some(r) => r.clone(),
+
+        None => return Err(Error::InvalidFrame),
+    };
+
+    let largest_ack = first.end - 1;
+    let first_block = first.end - first.start - 1;
+
+    let mut smallest = first.start;
+
+    let mut len = 1 +
+                  octets::varint_len(largest_ack) +
+                  octets::varint_len(ack_delay) +
+                  octets::varint_len(first_block);
+
+    // The counters follow the ranges, so they must always fit.
+    if let Some(ecn) = ecn {
+        len += octets::varint_len(ecn.ect0) +
+               octets::varint_len(ecn.ect1) +
+               octets::varint_len(ecn.ce);
+    }
+
+    // Block count of 0.
+    if len + 1 > budget {
+        return Err(Error::BufferTooShort);
+    }
+
+    let mut blocks = Vec::new();
+
+    for r in iter {
+        let gap = smallest - r.end - 1;
+        let ack_block = r.end - r.start - 1;
+
+        let block_len = octets::varint_len(gap) +
+                        octets::varint_len(ack_block);
+
+        let count_len = octets::varint_len(blocks.len() as u64 + 1);
+
+        if len + block_len + count_len > budget {
+            break;
+        }
+
+        len += block_len;
+
+        blocks.push((gap, ack_block));
+
+        smallest = r.start;
+    }
+
+    let before = b.cap();
+
+    let ty = if ecn.is_some() { FrameType::AckEcn } else { FrameType::Ack };
+
+    b.put_varint(ty.into())?;
+
+    b.put_varint(largest_ack)?;
+    b.put_varint(ack_delay)?;
+    b.put_varint(blocks.len() as u64)?;
+    b.put_varint(first_block)?;
+
+    for (gap, ack_block) in blocks {
+        b.put_varint(gap)?;
+        b.put_varint(ack_block)?;
+    }
+
+    if let Some(ecn) = ecn {
+        b.put_varint(ecn.ect0)?;
+        b.put_varint(ecn.ect1)?;
+        b.put_varint(ecn.ce)?;
+    }
+
+    Ok((before - b.cap(), smallest))
+}
+
+// Selects which of the pending frames to send in a packet with `budget`
+// bytes available for frames. Control frames (ACK, and the other frames that
+// manage the connection and streams without carrying data) are picked first,
+// in order, followed by CRYPTO and DATAGRAM frames, and then STREAM frames.
+// If a STREAM frame doesn't fit entirely, it's split so that the remaining
+// space is filled and the rest is deferred. PADDING only uses what's left.
+//
+// Returns the selected frames and the ones deferred to a later packet.
+pub fn schedule(pending: Vec<FrameOwned>, budget: usize)
+                                    -> (Vec<FrameOwned>, Vec<FrameOwned>) {
+    let mut selected = Vec::new();
+    let mut deferred = Vec::new();
+
+    let mut left = budget;
+
+    let (padding, pending): (Vec<FrameOwned>, Vec<FrameOwned>) =
+        pending.into_iter()
+               .partition(|f| matches!(f, Frame::Padding { .. }));
+
+    let (streams, pending): (Vec<FrameOwned>, Vec<FrameOwned>) =
+        pending.into_iter()
+               .partition(|f| matches!(f, Frame::Stream { .. }));
+
+    let (data, control): (Vec<FrameOwned>, Vec<FrameOwned>) =
+        pending.into_iter()
+               .partition(|f| matches!(f, Frame::Crypto { .. } |
+                                          Frame::Datagram { .. }));
+
+    for frame in control.into_iter().chain(data) {
+        if frame.wire_len() <= left {
+            left -= frame.wire_len();
+            selected.push(frame);
+        } else {
+            deferred.push(frame);
+        }
+    }
+
+    for frame in streams {
+        if frame.wire_len() <= left {
+            left -= frame.wire_len();
+            selected.push(frame);
+            continue;
+        }
+
+        match frame {
+            Frame::Stream { stream_id, offset, data, fin } => {
+                let hdr_len = 1 +                             // frame type
+                              octets::varint_len(stream_id) + // stream_id
+                              octets::varint_len(offset);     // offset
+
+                // Need room for at least the length field and 1 byte of data.
+                if left <= hdr_len + 1 {
+                    deferred.push(Frame::Stream {
+                        stream_id,
+                        offset,
+                        data,
+                        fin,
+                    });
+
+                    continue;
+                }
+
+                let avail = left - hdr_len;
+                let split_len = avail - octets::varint_len(avail as u64);
+
+                let mut first = data.into_owned();
+                let last = first.split_off(split_len);
+
+                left = 0;
+
+                selected.push(FrameOwned::stream(stream_id, offset, first,
+                                                 false));
+
+                deferred.push(FrameOwned::stream(stream_id,
+                                                 offset + split_len as u64,
+                                                 last, fin));
+            },
+
+            _ => unreachable!(),
+        }
+    }
+
+    for frame in padding {
+        if frame.wire_len() <= left {
+            left -= frame.wire_len();
+            selected.push(frame);
+        } else {
+            deferred.push(frame);
+        }
+    }
+
+    (selected, deferred)
+}
+
+// Splits stream data into STREAM frames carrying at most `max_lens[i]` bytes
+// of data each, e.g. to spread it over several packets. Only the frame that
+// carries the last byte of data has the FIN bit set (if `fin` is true). If
+// the limits don't cover all of the data, the data left over is not
+// included in any frame, and no frame carries FIN. Limits of 0 are skipped,
+// except to send a FIN for empty data.
+pub fn split_stream_into<'a>(stream_id: u64, offset: u64, data: &'a [u8],
+                             fin: bool, max_lens: &[usize]) -> Vec<Frame<'a>> {
+    let mut frames = Vec::new();
+
+    let mut off = 0;
+
+    for max_len in max_lens {
+        // Still emit one frame for empty data, so that a FIN can be sent.
+        if off == data.len() && !frames.is_empty() {
+            break;
+        }
+
+        let len = cmp::min(*max_len, data.len() - off);
+
+        if len == 0 && !(fin && data.is_empty()) {
+            continue;
+        }
+
+        frames.push(Frame::Stream {
+            stream_id,
+            offset: offset + off as u64,
+            data: Cow::Borrowed(&data[off..off + len]),
+            fin: fin && off + len == data.len(),
+        });
+
+        off += len;
+    }
+
+    frames
+}
+
+// Returns the number of bytes the frames take on the wire excluding any
+// PADDING, i.e. the bytes that are actually useful to the peer.
+pub fn useful_len(frames: &[Frame]) -> usize {
+    frames.iter()
+          .filter(|f| !matches!(f, Frame::Padding { .. }))
+          .map(|f| f.wire_len())
+          .sum()
+}
+
+// Returns the frames for a keep-alive packet: a single PING, followed by
+// enough PADDING for the frames to take `probe_size` bytes when probing the
+// path MTU.
+pub fn keepalive_frames(probe_size: Option<usize>) -> Vec<FrameOwned> {
+    let mut frames = vec![Frame::ping()];
+
+    if let Some(probe_size) = probe_size {
+        let ping_len = Frame::Ping.wire_len();
+
+        if probe_size > ping_len {
+            frames.push(Frame::padding(probe_size - ping_len));
+        }
+    }
+
+    frames
+}
+
+// Whether a packet containing the given frames counts as in flight for
+// congestion control, i.e. whether it contains anything other than ACK and
+// close frames.
+pub fn packet_in_flight(frames: &[Frame]) -> bool {
+    frames.iter().any(|f| f.counts_toward_cwnd())
+}
+
+// Computes a digest of a sequence of frames, e.g. to compare what two
+// endpoints think a packet contained. Only the frame types and their fields
+// are hashed, STREAM and CRYPTO payloads are represented by their length.
+//
+// FNV-1a is used rather than std's DefaultHasher, whose output isn't
+// guaranteed to be stable across Rust releases.
+pub fn frames_digest(frames: &[Frame]) -> u64 {
+    let mut h = FNV_OFFSET_BASIS;
+
+    for frame in frames {
+        h = fnv1a(h, &[frame.first_byte()]);
+
+        match frame {
+            Frame::Padding { len } => {
+                h = fnv1a_u64(h, *len as u64);
+            },
+
+            Frame::ResetStream { stream_id, error_code, final_size } => {
+                h = fnv1a_u64(h, *stream_id);
+                h = fnv1a_u64(h, u64::from(*error_code));
+                h = fnv1a_u64(h, *final_size);
+            },
+
+            Frame::ConnectionClose { error_code, frame_type, reason } => {
+                h = fnv1a_u64(h, u64::from(*error_code));
+                h = fnv1a_u64(h, *frame_type);
+                h = fnv1a_u64(h, reason.len() as u64);
+                h = fnv1a(h, reason);
+            },
+
+            Frame::ApplicationClose { error_code, reason } => {
+                h = fnv1a_u64(h, u64::from(*error_code));
+                h = fnv1a_u64(h, reason.len() as u64);
+                h = fnv1a(h, reason);
+            },
+
+            Frame::MaxData { max } => {
+                h = fnv1a_u64(h, *max);
+            },
+
+            Frame::MaxStreamData { stream_id, max } => {
+                h = fnv1a_u64(h, *stream_id);
+                h = fnv1a_u64(h, *max);
+            },
+
+            Frame::MaxStreamId { max } => {
+                h = fnv1a_u64(h, *max);
+            },
+
+            Frame::Ping => (),
+
+            Frame::DataBlocked { limit } => {
+                h = fnv1a_u64(h, *limit);
+            },
+
+            Frame::StreamDataBlocked { stream_id, limit } => {
+                h = fnv1a_u64(h, *stream_id);
+                h = fnv1a_u64(h, *limit);
+            },
+
+            Frame::StreamIdBlocked { limit } => {
+                h = fnv1a_u64(h, *limit);
+            },
+
+            Frame::NewConnectionId { seq_num, conn_id, reset_token } => {
+                h = fnv1a_u64(h, *seq_num);
+                h = fnv1a_u64(h, conn_id.len() as u64);
+                h = fnv1a(h, conn_id);
+                h = fnv1a(h, reset_token);
+            },
+
+            Frame::StopSending { stream_id, error_code } => {
+                h = fnv1a_u64(h, *stream_id);
+                h = fnv1a_u64(h, u64::from(*error_code));
+            },
+
+            Frame::ACK { ack_delay, ranges, ecn } => {
+                h = fnv1a_u64(h, *ack_delay);
+                h = fnv1a_u64(h, ranges.len() as u64);
+
+                for r in ranges.iter() {
+                    h = fnv1a_u64(h, r.start);
+                    h = fnv1a_u64(h, r.end);
+                }
+
+                if let Some(ecn) = ecn {
+                    h = fnv1a_u64(h, ecn.ect0);
+                    h = fnv1a_u64(h, ecn.ect1);
+                    h = fnv1a_u64(h, ecn.ce);
+                }
+            },
+
+            Frame::PathChallenge { data } => {
+                h = fnv1a(h, data);
+            },
+
+            Frame::PathResponse { data } => {
+                h = fnv1a(h, data);
+            },
+
+            Frame::Crypto { offset, data } => {
+                h = fnv1a_u64(h, *offset);
+                h = fnv1a_u64(h, data.len() as u64);
+            },
+
+            Frame::Stream { stream_id, offset, data, .. } => {
+                h = fnv1a_u64(h, *stream_id);
+                h = fnv1a_u64(h, *offset);
+                h = fnv1a_u64(h, data.len() as u64);
+            },
+
+            Frame::NewToken { token } => {
+                h = fnv1a_u64(h, token.len() as u64);
+                h = fnv1a(h, token);
+            },
+
+            Frame::HandshakeDone => (),
+
+            Frame::Datagram { data } => {
+                h = fnv1a_u64(h, data.len() as u64);
+            },
+
+            Frame::UnknownExt { frame_type, raw } => {
+                h = fnv1a_u64(h, *frame_type);
+                h = fnv1a_u64(h, raw.len() as u64);
+                h = fnv1a(h, raw);
+            },
+        }
+    }
+
+    h
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(mut h: u64, data: &[u8]) -> u64 {
+    for b in data {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+
+    h
+}
+
+fn fnv1a_u64(h: u64, v: u64) -> u64 {
+    fnv1a(h, &v.to_be_bytes())
+}
+
+// Checks the parsed fields against their maximum allowed values.
+fn validate_frame(frame: &Frame) -> Result<()> {
+    match frame {
+        Frame::Crypto { offset, data }
+            if *offset + data.len() as u64 > MAX_STREAM_OFFSET =>
+                Err(Error::FinalSizeError),
+
+        Frame::Stream { offset, data, .. }
+            if *offset + data.len() as u64 > MAX_STREAM_OFFSET =>
+                Err(Error::FinalSizeError),
+
+        _ => Ok(()),
+    }
+}
+
+fn parse_ack_frame<'a>(ecn: bool, b: &mut octets::Bytes, strict: bool)
+                                                    -> Result<Frame<'a>> {
+    let largest_ack = get_varint(b, strict)?;
+    let ack_delay = get_varint(b, strict)?;
+    let block_count = get_varint(b, strict)?;
+    let first_block = get_varint(b, strict)?;
+
+    // The first block can't extend below packet number 0.
+    let mut smallest_ack = largest_ack.checked_sub(first_block)
+                                      .ok_or(Error::InvalidFrame)?;
+
+    // Each block takes at least 2 bytes, so a count that couldn't possibly
+    // fit in the rest of the buffer is rejected upfront.
+    if block_count > (b.cap() / 2) as u64 {
+        return Err(Error::BufferTooShort);
+    }
+
+    let mut ranges = ranges::RangeSet::default();
+
+    ranges.insert_range(smallest_ack..largest_ack + 1);
+
+    for _i in 0..block_count {
+        let gap = get_varint(b, strict)?;
+
+        // Packet number of the largest packet in the block.
+        let largest_ack = smallest_ack.checked_sub(gap)
+                                      .and_then(|v| v.checked_sub(2))
+                                      .ok_or(Error::InvalidFrame)?;
+
+        let ack_block = get_varint(b, strict)?;
+
+        smallest_ack = largest_ack.checked_sub(ack_block)
+                                  .ok_or(Error::InvalidFrame)?;
+
+        ranges.insert_range(smallest_ack..largest_ack + 1);
+    }
+
+    let ecn = if ecn {
+        Some(EcnCounts {
+            ect0: get_varint(b, strict)?,
+            ect1: get_varint(b, strict)?,
+            ce: get_varint(b, strict)?,
+        })
+    } else {
+        None
+    };
+
+    Ok(Frame::ACK {
+        ack_delay,
+        ranges,
+        ecn,
+    })
+}
+
+fn parse_datagram_frame<'a>(flags: u8, b: &'a mut octets::Bytes,
+                            strict: bool) -> Result<Frame<'a>> {
+    // Without a length the datagram extends to the end of the packet.
+    // A length that doesn't fit in usize can't fit in the buffer either.
+    let len = if flags & 0x01 != 0 {
+        usize::try_from(get_varint(b, strict)?)
+            .map_err(|_| Error::BufferTooShort)?
+    } else {
+        b.cap()
+    };
+
+    let data = Cow::Borrowed(b.get_bytes(len)?.into_slice());
+
+    Ok(Frame::Datagram {
+        data,
+    })
+}
+
+fn parse_new_connection_id_frame<'a>(b: &'a mut octets::Bytes, strict: bool)
+                                                    -> Result<Frame<'a>> {
+    // Unlike later drafts, draft-14 has no retire_prior_to field.
+    let seq_num = get_varint(b, strict)?;
+
+    // Check the declared length before copying anything out of the buffer.
+    let conn_id_len = b.get_u8()? as usize;
+    if conn_id_len == 0 || conn_id_len > MAX_CONN_ID_LEN {
+        return Err(Error::InvalidConnectionId);
+    }
+
+    // Both are borrowed from the buffer, so they need to be taken out of it
+    // in one go. get_bytes() fails if fewer bytes are left, so the token is
+    // always exactly RESET_TOKEN_LEN bytes long.
+    let rest = b.get_bytes(conn_id_len + RESET_TOKEN_LEN)?.into_slice();
+
+    let (conn_id, reset_token) = rest.split_at(conn_id_len);
+
+    Ok(Frame::NewConnectionId {
+        seq_num,
+        conn_id: Cow::Borrowed(conn_id),
+        reset_token: Cow::Borrowed(reset_token),
+    })
+}
+
+// Reads a varint, rejecting non-minimal encodings in strict mode.
+fn get_varint(b: &mut octets::Bytes, strict: bool) -> Result<u64> {
+    if strict {
+        b.get_varint_strict()
+    } else {
+        b.get_varint()
+    }
+}
+
+fn get_bytes_with_varint_length<'a>(b: &'a mut octets::Bytes, strict: bool)
+                                            -> Result<octets::Bytes<'a>> {
+    let len = usize::try_from(get_varint(b, strict)?)
+        .map_err(|_| Error::BufferTooShort)?;
+    b.get_bytes(len)
+}
+
+fn parse_path_data(b: &mut octets::Bytes) -> Result<[u8; 8]> {
+    let mut data: [u8; 8] = [0; 8];
+
+    data.copy_from_slice(b.get_bytes(8)?.as_ref());
+
+    Ok(data)
+}
+
+fn parse_stream_frame<'a>(first: u8, b: &'a mut octets::Bytes,
+                          strict: bool) -> Result<Frame<'a>> {
+
+    let stream_id = get_varint(b, strict)?;
+
+    let offset = if first & 0x04 != 0 {
+        get_varint(b, strict)?
+    } else {
+        0
+    };
+
+    let len = if first & 0x02 != 0 {
+        let len = get_varint(b, strict)?;
+
+        usize::try_from(len).map_err(|_| Error::TruncatedFrame {
+            declared: usize::MAX,
+            available: b.cap(),
+        })?
+    } else {
+        b.cap()
+    };
+
+    let fin = first & 0x01 != 0;
+
+    // Report both lengths, instead of just BufferTooShort, so that the
+    // mismatch shows up when debugging a malformed frame.
+    if len > b.cap() {
+        return Err(Error::TruncatedFrame {
+            declared: len,
+            available: b.cap(),
+        });
+    }
+
+    let data = Cow::Borrowed(b.get_bytes(len)?.into_slice());
+
+    Ok(Frame::Stream {
+        stream_id,
+        offset,
+        data,
+        fin,
+    })
+}
+
+// The frame types, which also provide constructors such as `Frame::ping()`
+// and `FrameOwned::padding()`, and the predicates used to classify a packet
+// by the frames it carries.
+pub mod prelude {
+    pub use super::Frame;
+    pub use super::FrameOwned;
+
+    // Whether a packet carrying the given frames needs to be acknowledged.
+    pub fn ack_eliciting(frames: &[Frame]) -> bool {
+        frames.iter().any(|f| f.is_ack_eliciting())
+    }
+
+    // Whether any of the given frames needs to be sent again if the packet
+    // carrying them is lost.
+    pub fn retransmittable(frames: &[Frame]) -> bool {
+        frames.iter().any(|f| f.is_retransmittable())
+    }
+}
+
+// Serializes byte payloads as hex strings, for frames to be readable once
+// dumped.
+#[cfg(feature = "serde")]
+mod hex {
+    use core::convert::TryFrom;
+
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+    use serde::de::Error;
+
+    pub fn serialize<T, S>(v: &T, s: S) -> Result<S::Ok, S::Error>
+            where T: AsRef<[u8]>, S: Serializer {
+        let hex: String = v.as_ref().iter()
+                                    .map(|b| format!("{:02x}", b))
+                                    .collect();
+
+        s.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+            where T: TryFrom<Vec<u8>>, D: Deserializer<'de> {
+        let hex = String::deserialize(d)?;
+        let hex = hex.as_bytes();
+
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+
+        let mut bytes = Vec::with_capacity(hex.len() / 2);
+
+        for pair in hex.chunks(2) {
+            match (nibble(pair[0]), nibble(pair[1])) {
+                (Some(hi), Some(lo)) => bytes.push(hi << 4 | lo),
+
+                _ => return Err(D::Error::custom("invalid hex digit")),
+            }
+        }
+
+        T::try_from(bytes)
+            .map_err(|_| D::Error::custom("unexpected payload length"))
+    }
+
+    fn nibble(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "serde")]
+    use serde_json;
+
+    fn ack_frame(largest_ack: u64, ack_delay: u64) -> FrameOwned {
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(largest_ack).unwrap();
+
+        Frame::ACK {
+            ack_delay,
+            ranges,
+            ecn: None,
+        }
+    }
+
+    // A fixed-seed xorshift PRNG, so that generated frames are the same on
+    // every run and a failure reproduces without any saved seed. This is
+    // used instead of proptest, which isn't among the dev-dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, max: u64) -> u64 {
+            self.next() % max
+        }
+
+        // Returns a value that takes a random number of bytes as a varint.
+        fn varint(&mut self) -> u64 {
+            let bits = [6, 14, 30, 62][self.below(4) as usize];
+            self.next() & ((1 << bits) - 1)
+        }
+
+        fn bytes(&mut self, min: usize, max: usize) -> Vec<u8> {
+            let len = min + self.below((max - min + 1) as u64) as usize;
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        fn array(&mut self) -> [u8; 8] {
+            let mut data = [0; 8];
+
+            for b in data.iter_mut() {
+                *b = self.next() as u8;
+            }
+
+            data
+        }
+    }
+
+    const ARBITRARY_FRAME_KINDS: u64 = 22;
+
+    // Builds a random valid frame of the given kind, with fields spanning
+    // all the varint sizes.
+    fn arbitrary_frame(rng: &mut Rng, kind: u64) -> FrameOwned {
+        match kind {
+            0 => Frame::Padding { len: 1 + rng.below(100) as usize },
+
+            1 => Frame::ResetStream {
+                stream_id: rng.varint(),
+                error_code: rng.next() as u16,
+                final_size: rng.varint(),
+            },
+
+            2 => Frame::ConnectionClose {
+                error_code: rng.next() as u16,
+                frame_type: rng.varint(),
+                reason: Cow::Owned(rng.bytes(0, 64)),
+            },
+
+            3 => Frame::ApplicationClose {
+                error_code: rng.next() as u16,
+                reason: Cow::Owned(rng.bytes(0, 64)),
+            },
+
+            4 => Frame::MaxData { max: rng.varint() },
+
+            5 => Frame::MaxStreamData {
+                stream_id: rng.varint(),
+                max: rng.varint(),
+            },
+
+            6 => Frame::MaxStreamId { max: rng.varint() },
+
+            7 => Frame::Ping,
+
+            8 => Frame::DataBlocked { limit: rng.varint() },
+
+            9 => Frame::StreamDataBlocked {
+                stream_id: rng.varint(),
+                limit: rng.varint(),
+            },
+
+            10 => Frame::StreamIdBlocked { limit: rng.varint() },
+
+            11 => Frame::NewConnectionId {
+                seq_num: rng.varint(),
+                conn_id: Cow::Owned(rng.bytes(1, MAX_CONN_ID_LEN)),
+                reset_token: Cow::Owned(rng.bytes(16, 16)),
+            },
+
+            12 => Frame::StopSending {
+                stream_id: rng.varint(),
+                error_code: rng.next() as u16,
+            },
+
+            13 => {
+                let mut ranges = ranges::RangeSet::default();
+
+                // Add ranges from the largest down, with a gap of at least
+                // one packet between them.
+                let mut end = rng.varint() + 1;
+
+                for _ in 0..1 + rng.below(8) {
+                    let len = cmp::min(1 + rng.below(1000), end);
+                    ranges.insert_range(end - len..end);
+
+                    let gap = 1 + rng.below(1000);
+                    if end - len <= gap {
+                        break;
+                    }
+
+                    end -= len + gap;
+                }
+
+                let ecn = if rng.below(2) == 0 {
+                    Some(EcnCounts {
+                        ect0: rng.varint(),
+                        ect1: rng.varint(),
+                        ce: rng.varint(),
+                    })
+                } else {
+                    None
+                };
+
+                Frame::ACK {
+                    ack_delay: rng.varint(),
+                    ranges,
+                    ecn,
+                }
+            },
+
+            14 => Frame::PathChallenge { data: rng.array() },
+
+            15 => Frame::PathResponse { data: rng.array() },
+
+            16 => {
+                let data = rng.bytes(0, 64);
+
+                Frame::Crypto {
+                    offset: cmp::min(rng.varint(),
+                                     MAX_STREAM_OFFSET - data.len() as u64),
+                    data: Cow::Owned(data),
+                }
+            },
+
+            17 => {
+                let data = rng.bytes(0, 64);
+
+                Frame::Stream {
+                    stream_id: rng.varint(),
+                    offset: cmp::min(rng.varint(),
+                                     MAX_STREAM_OFFSET - data.len() as u64),
+                    data: Cow::Owned(data),
+                    fin: rng.below(2) == 0,
+                }
+            },
+
+            18 => Frame::NewToken { token: Cow::Owned(rng.bytes(1, 64)) },
+
+            19 => Frame::HandshakeDone,
+
+            20 => Frame::Datagram { data: Cow::Owned(rng.bytes(0, 64)) },
+
+            21 => Frame::UnknownExt {
+                frame_type: LENGTH_PREFIXED_EXT_TYPES[0],
+                raw: rng.bytes(0, 64),
+            },
+
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn padding() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::Padding { len: 1 };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 1);
+        assert_eq!(&d[..wire_len], [0 as u8]);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn padding_run() {
+        let mut d: [u8; 310] = [0; 310];
+
+        let frame = Frame::Padding { len: 300 };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 300);
+        assert_eq!(frame.wire_len(), 300);
+
+        d[300] = 0x07;
+
+        let mut b = octets::Bytes::new(&mut d[..301]);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), Frame::Ping);
+        assert_eq!(b.cap(), 0);
+    }
+
+    #[test]
+    fn padding_non_minimal() {
+        // A 2-byte varint encoding of frame type 0x00 is not minimal. The
+        // lenient parser still accepts it as PADDING, the strict one
+        // doesn't.
+        let mut d: [u8; 3] = [0x40, 0x00, 0x00];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(),
+                   Frame::Padding { len: 3 });
+        assert_eq!(b.cap(), 0);
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes_strict(&mut b), Err(Error::InvalidVarint));
+    }
+
+    #[test]
+    fn connection_close() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::ConnectionClose {
+            error_code: 0xbeef,
+            frame_type: 523423,
+            reason: Cow::Borrowed(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 20);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn connection_close_borrows_reason() {
+        // error_code = 0x000a, frame_type = 0, reason = "bad"
+        let mut d: [u8; 8] = [0x02, 0x00, 0x0a, 0x00, 0x03, b'b', b'a', b'd'];
+
+        let reason_ptr = d[5..].as_ptr();
+
+        let mut b = octets::Bytes::new(&mut d);
+
+        match Frame::from_bytes(&mut b).unwrap() {
+            Frame::ConnectionClose { reason: Cow::Borrowed(reason), .. } => {
+                assert_eq!(reason, b"bad");
+                assert_eq!(reason.as_ptr(), reason_ptr);
+            },
+
+            f => panic!("unexpected frame {:?}", f),
+        }
+    }
+
+    #[test]
+    fn new_connection_id_borrows() {
+        // seq_num = 1, conn_id = 0xbadc0ffe
+        let mut d = vec![0x0b, 0x01, 0x04, 0xba, 0xdc, 0x0f, 0xfe];
+        d.extend_from_slice(&[0x42; 16]);
+
+        let mut b = octets::Bytes::new(&mut d);
+
+        match Frame::from_bytes(&mut b).unwrap() {
+            Frame::NewConnectionId {
+                conn_id: Cow::Borrowed(conn_id),
+                reset_token: Cow::Borrowed(reset_token),
+                ..
+            } => {
+                assert_eq!(conn_id, [0xba, 0xdc, 0x0f, 0xfe]);
+                assert_eq!(reset_token, &[0x42; 16][..]);
+            },
+
+            f => panic!("unexpected frame {:?}", f),
+        }
+    }
+
+    #[test]
+    fn application_close() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::ApplicationClose {
+            error_code: 0xbeef,
+            reason: Cow::Borrowed(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 16);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn connection_close_empty_reason() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::ConnectionClose {
+            error_code: 0xbeef,
+            frame_type: 0,
+            reason: Cow::Borrowed(&[]),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 5);
+        assert_eq!(wire_len, frame.wire_len());
+        assert_eq!(&d[..wire_len], [0x02, 0xbe, 0xef, 0x00, 0x00]);
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn application_close_empty_reason() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::ApplicationClose {
+            error_code: 0xbeef,
+            reason: Cow::Borrowed(&[]),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 4);
+        assert_eq!(wire_len, frame.wire_len());
+        assert_eq!(&d[..wire_len], [0x03, 0xbe, 0xef, 0x00]);
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn ping() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::Ping;
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 1);
+        assert_eq!(&d[..wire_len], [0x07 as u8]);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn ping_padding_constructors() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frames = vec![FrameOwned::ping(), FrameOwned::padding(2)];
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+
+            let mut len = 0;
+
+            for frame in &frames {
+                len += frame.to_bytes(&mut b).unwrap();
+            }
+
+            len
+        };
+
+        assert_eq!(wire_len, 3);
+        assert_eq!(&d[..wire_len], [0x07, 0x00, 0x00]);
+
+        let mut b = octets::Bytes::new(&mut d[..wire_len]);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), Frame::Ping);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(),
+                   Frame::Padding { len: 2 });
+    }
+
+    #[test]
+    fn new_connection_id() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::NewConnectionId {
+            seq_num: 123213,
+            conn_id: Cow::Borrowed(&[
+                1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+            ]),
+            reset_token: Cow::Borrowed(&[0x42; 16]),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 37);
+        assert_eq!(frame.wire_len(), 37);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn ack() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(4..7);
+        ranges.insert_range(9..12);
+        ranges.insert_range(15..19);
+        ranges.insert_range(3000..5000);
+
+        let frame = Frame::ACK {
+            ack_delay: 874656534,
+            ranges,
+            ecn: None,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 17);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn ack_zero_delay() {
+        let mut d: [u8; 128] = [42; 128];
+
+        // There's no way to omit the ACK delay, a zero delay still takes one
+        // byte.
+        let frame = ack_frame(2163721632, 0);
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 12);
+        assert_eq!(wire_len, frame.wire_len());
+        assert_eq!(d[9], 0x00);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn ack_max_delay() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = ack_frame(2163721632, 4_611_686_018_427_387_903);
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 19);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn ack_unaligned() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = ack_frame(2163721632, 874656534);
+
+        // Encode the frame at an odd offset so that multi-byte fields are
+        // not aligned to their size.
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d[1..]);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 15);
+
+        {
+            let mut b = octets::Bytes::new(&mut d[1..]);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn crypto() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Crypto {
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 18);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 19);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn stream_eq_ignores_cursor() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        // Encode the frame after some unrelated bytes, so that the parsed
+        // data is borrowed from a buffer whose cursor is not at the start.
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            b.skip(7).unwrap();
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 19);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            b.skip(7).unwrap();
+
+            let parsed = Frame::from_bytes(&mut b).unwrap();
+            assert_eq!(parsed, frame);
+            assert_eq!(parsed, FrameOwned::stream(32, 1230976, data.to_vec(),
+                                                  true));
+        }
+    }
+
+    #[test]
+    fn stream_owned() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = FrameOwned::stream(32, 1230976, data, true);
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 19);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn first_byte() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        assert_eq!(frame.first_byte(), 0x17);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        assert_eq!(d[0], frame.first_byte());
+
+        assert_eq!(Frame::Ping.first_byte(), 0x07);
+        assert_eq!(Frame::Padding { len: 1 }.first_byte(), 0x00);
+    }
+
+    #[test]
+    fn largest_acked() {
+        let frame = ack_frame(2163721632, 874656534);
+
+        assert_eq!(frame.largest_acked(), Some(2163721632));
+
+        assert_eq!(Frame::Ping.largest_acked(), None);
+        assert_eq!(Frame::Padding { len: 1 }.largest_acked(), None);
+    }
+
+    #[test]
+    fn schedule_control_first() {
+        let ack = ack_frame(2163721632, 874656534);
+
+        let stream = FrameOwned::stream(4, 0, vec![0xab; 100], false);
+
+        let (selected, deferred) =
+            schedule(vec![stream, Frame::Ping, ack], 40);
+
+        assert_eq!(selected.len(), 3);
+        assert_eq!(selected[0], Frame::Ping);
+        assert_eq!(selected[1], ack_frame(2163721632, 874656534));
+
+        // ACK is 15 bytes and PING 1, so 24 bytes are left for the STREAM
+        // frame, 4 of which are taken by its header.
+        assert_eq!(selected[2], FrameOwned::stream(4, 0, vec![0xab; 20],
+                                                   false));
+
+        let len: usize = selected.iter().map(|f| f.wire_len()).sum();
+        assert_eq!(len, 40);
+
+        assert_eq!(deferred, vec![FrameOwned::stream(4, 20, vec![0xab; 80],
+                                                     false)]);
+    }
+
+    #[test]
+    fn schedule_split_keeps_fin() {
+        let first = FrameOwned::stream(4, 0, vec![0xab; 10], false);
+        let second = FrameOwned::stream(8, 0, vec![0xcd; 100], true);
+
+        let (selected, deferred) =
+            schedule(vec![first, second], 50);
+
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0], FrameOwned::stream(4, 0, vec![0xab; 10],
+                                                   false));
+
+        // 36 bytes are left after the first 14 bytes STREAM frame, and the
+        // header of the second one takes 4 bytes.
+        assert_eq!(selected[1], FrameOwned::stream(8, 0, vec![0xcd; 32],
+                                                   false));
+
+        assert_eq!(deferred, vec![FrameOwned::stream(8, 32, vec![0xcd; 68],
+                                                     true)]);
+    }
+
+    #[test]
+    fn schedule_defer_all() {
+        let stream = FrameOwned::stream(4, 0, vec![0xab; 100], false);
+
+        let (selected, deferred) = schedule(vec![stream, Frame::Ping], 4);
+
+        assert_eq!(selected, vec![Frame::Ping]);
+        assert_eq!(deferred, vec![FrameOwned::stream(4, 0, vec![0xab; 100],
+                                                     false)]);
+    }
+
+    #[test]
+    fn digest() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frames = vec![
+            ack_frame(2163721632, 874656534),
+
+            Frame::Stream {
+                stream_id: 32,
+                offset: 1230976,
+                data: Cow::Borrowed(&data),
+                fin: true
+            },
+
+            Frame::Ping,
+        ];
+
+        let same = vec![
+            ack_frame(2163721632, 874656534),
+
+            FrameOwned::stream(32, 1230976, data.to_vec(), true),
+
+            Frame::Ping,
+        ];
+
+        assert_eq!(frames_digest(&frames), frames_digest(&same));
+
+        let different = vec![
+            ack_frame(2163721632, 874656534),
+
+            FrameOwned::stream(32, 1230976, data.to_vec(), false),
+
+            Frame::Ping,
+        ];
+
+        assert_ne!(frames_digest(&frames), frames_digest(&different));
+
+        assert_ne!(frames_digest(&frames), frames_digest(&frames[..2]));
+    }
+
+    #[test]
+    fn useful_len_no_padding() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let mut frames = vec![
+            Frame::Ping,
+
+            Frame::Stream {
+                stream_id: 32,
+                offset: 1230976,
+                data: Cow::Borrowed(&data),
+                fin: true
+            },
+        ];
+
+        assert_eq!(useful_len(&frames), 20);
+
+        frames.push(Frame::Padding { len: 10 });
+
+        let wire_len: usize = frames.iter().map(|f| f.wire_len()).sum();
+
+        assert_eq!(wire_len, 30);
+        assert_eq!(useful_len(&frames), 20);
+    }
+
+    #[test]
+    fn split_stream_fin_last() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frames = split_stream_into(32, 1000, &data, true, &[5, 5, 5]);
+
+        assert_eq!(frames, vec![
+            Frame::Stream {
+                stream_id: 32,
+                offset: 1000,
+                data: Cow::Borrowed(&data[..5]),
+                fin: false,
+            },
+
+            Frame::Stream {
+                stream_id: 32,
+                offset: 1005,
+                data: Cow::Borrowed(&data[5..10]),
+                fin: false,
+            },
+
+            Frame::Stream {
+                stream_id: 32,
+                offset: 1010,
+                data: Cow::Borrowed(&data[10..]),
+                fin: true,
+            },
+        ]);
+    }
+
+    #[test]
+    fn split_stream_short_limits() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        // Not all data fits, so FIN can't be sent.
+        let frames = split_stream_into(32, 0, &data, true, &[4, 4]);
+
+        assert_eq!(frames, vec![
+            Frame::Stream {
+                stream_id: 32,
+                offset: 0,
+                data: Cow::Borrowed(&data[..4]),
+                fin: false,
+            },
+
+            Frame::Stream {
+                stream_id: 32,
+                offset: 4,
+                data: Cow::Borrowed(&data[4..8]),
+                fin: false,
+            },
+        ]);
+
+        // Extra limits are not used once all data is consumed.
+        let frames = split_stream_into(32, 0, &data, true, &[10, 10, 10]);
+        assert_eq!(frames.len(), 2);
+
+        let frames = split_stream_into(32, 12, &[], true, &[10]);
+        assert_eq!(frames, vec![FrameOwned::stream(32, 12, Vec::new(), true)]);
+    }
+
+    #[test]
+    fn crypto_range() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Crypto {
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+        };
+
+        assert_eq!(frame.crypto_range(), Some((1230976, 1230988)));
+        assert!(!frame.is_flow_controlled());
+
+        let frame = FrameOwned::stream(32, 1230976, data.to_vec(), true);
+
+        assert_eq!(frame.crypto_range(), None);
+        assert!(frame.is_flow_controlled());
+    }
+
+    #[test]
+    fn stream_range() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        assert_eq!(frame.stream_range(), Some((1230976, 1230988, true)));
+
+        let frame = FrameOwned::stream(32, 12, Vec::new(), false);
+
+        assert_eq!(frame.stream_range(), Some((12, 12, false)));
+
+        let frame = Frame::Crypto {
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+        };
+
+        assert_eq!(frame.stream_range(), None);
+    }
+
+    #[test]
+    fn new_connection_id_too_long() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::NewConnectionId {
+            seq_num: 123213,
+            conn_id: Cow::Borrowed(&[0xba; 21]),
+            reset_token: Cow::Borrowed(&[0x42; 16]),
+        };
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b),
+                   Err(Error::InvalidConnectionId));
+    }
+
+    #[test]
+    fn new_connection_id_empty() {
+        // seq_num = 1, conn_id length = 0, followed by the reset token.
+        let mut d = vec![0x0b, 0x01, 0x00];
+        d.extend_from_slice(&[0x42; 16]);
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b),
+                   Err(Error::InvalidConnectionId));
+    }
+
+    #[test]
+    fn new_connection_id_length_too_large() {
+        // Only the 21-byte length prefix is present, so the check needs to
+        // happen before trying to read the connection ID itself.
+        let mut d = [0x0b, 0x01, 21];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b),
+                   Err(Error::InvalidConnectionId));
+    }
+
+    #[test]
+    fn new_connection_id_short_reset_token() {
+        let mut d = vec![0x0b, 0x01, 0x04, 0xba, 0xdc, 0x0f, 0xfe];
+        d.extend_from_slice(&[0x42; 15]);
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn stream_offset_too_large() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = FrameOwned::stream(32, MAX_STREAM_OFFSET, vec![1], true);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::FinalSizeError));
+
+        let frame = FrameOwned::stream(32, MAX_STREAM_OFFSET, Vec::new(), true);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+    }
+
+    #[test]
+    fn crypto_offset_too_large() {
+        // offset = 2^62 - 2, length = 2
+        let mut d: [u8; 12] = [
+            0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0x02, 1, 2
+        ];
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b), Err(Error::FinalSizeError));
+        }
+
+        // offset = 2^62 - 2, length = 1
+        d[9] = 0x01;
+
+        let mut b = octets::Bytes::new(&mut d[..11]);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), Frame::Crypto {
+            offset: MAX_STREAM_OFFSET - 1,
+            data: Cow::Borrowed(&[1]),
+        });
+    }
+
+    #[test]
+    fn append_to_vec() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let first = ack_frame(2163721632, 874656534);
+
+        let second = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        let third = Frame::Ping;
+
+        let mut d = Vec::new();
+
+        assert_eq!(first.append_to_vec(&mut d), Ok(15));
+        assert_eq!(second.append_to_vec(&mut d), Ok(19));
+        assert_eq!(third.append_to_vec(&mut d), Ok(1));
+
+        assert_eq!(d.len(), 35);
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), first);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), second);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), third);
+        assert_eq!(b.cap(), 0);
+    }
+
+    #[test]
+    fn ack_against_sent() {
+        let frame = ack_frame(2163721632, 874656534);
+
+        assert_eq!(validate_ack_against_sent(&frame, 2163721632), Ok(()));
+        assert_eq!(validate_ack_against_sent(&frame, 2163721633), Ok(()));
+
+        assert_eq!(validate_ack_against_sent(&frame, 2163721631),
+                   Err(Error::InvalidFrame));
+
+        assert_eq!(validate_ack_against_sent(&Frame::Ping, 0), Ok(()));
+    }
+
+    #[test]
+    fn new_connection_id_seq() {
+        let frame = Frame::NewConnectionId {
+            seq_num: 5,
+            conn_id: Cow::Borrowed(&[0xba, 0xdc, 0x0f, 0xfe]),
+            reset_token: Cow::Borrowed(&[0x42; 16]),
+        };
+
+        assert_eq!(validate_new_connection_id_seq(0, &frame), Ok(()));
+        assert_eq!(validate_new_connection_id_seq(4, &frame), Ok(()));
+
+        assert_eq!(validate_new_connection_id_seq(5, &frame),
+                   Err(Error::InvalidFrame));
+        assert_eq!(validate_new_connection_id_seq(6, &frame),
+                   Err(Error::InvalidFrame));
+
+        assert_eq!(validate_new_connection_id_seq(6, &Frame::Ping), Ok(()));
+    }
+
+    #[test]
+    fn counts_toward_cwnd() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let ack = ack_frame(2163721632, 874656534);
+
+        assert!(!ack.counts_toward_cwnd());
+
+        assert!(!Frame::ConnectionClose {
+            error_code: 0xbeef,
+            frame_type: 523423,
+            reason: Cow::Borrowed(&[]),
+        }.counts_toward_cwnd());
+
+        assert!(!Frame::ApplicationClose {
+            error_code: 0xbeef,
+            reason: Cow::Borrowed(&[]),
+        }.counts_toward_cwnd());
+
+        assert!(Frame::Padding { len: 1 }.counts_toward_cwnd());
+        assert!(Frame::Ping.counts_toward_cwnd());
+
+        assert!(Frame::NewConnectionId {
+            seq_num: 123213,
+            conn_id: Cow::Borrowed(&[1, 2, 3, 4]),
+            reset_token: Cow::Borrowed(&[0x42; 16]),
+        }.counts_toward_cwnd());
+
+        assert!(Frame::Crypto {
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+        }.counts_toward_cwnd());
+
+        assert!(FrameOwned::stream(32, 1230976, data.to_vec(), true)
+                    .counts_toward_cwnd());
+
+        // An ACK-only packet doesn't count, but adding any other frame makes
+        // the whole packet count.
+        let mut frames = vec![ack];
+        assert!(!frames.iter().any(|f| f.counts_toward_cwnd()));
+
+        frames.push(Frame::Ping);
+        assert!(frames.iter().any(|f| f.counts_toward_cwnd()));
+    }
+
+    #[test]
+    fn cursor() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap() +
+                Frame::Ping.to_bytes(&mut b).unwrap()
+        };
+
+        let buf_start = d.as_ptr() as usize;
+        let buf_end = buf_start + wire_len;
+
+        let mut b = octets::Bytes::new(&mut d[..wire_len]);
+        let mut cursor = FrameCursor::new(&mut b);
+
+        match cursor.next_frame() {
+            Some(Ok(Frame::Stream { data: Cow::Borrowed(v), .. })) => {
+                assert_eq!(v, &data);
+
+                let ptr = v.as_ptr() as usize;
+                assert!(ptr >= buf_start && ptr + v.len() <= buf_end);
+            },
+
+            _ => panic!("expected borrowed STREAM frame"),
+        }
+
+        assert_eq!(cursor.next_frame(), Some(Ok(Frame::Ping)));
+        assert_eq!(cursor.next_frame(), None);
+    }
+
+    #[test]
+    fn cursor_error() {
+        let mut d: [u8; 3] = [0x07, 0x1f, 0x07];
+
+        let mut b = octets::Bytes::new(&mut d);
+        let mut cursor = FrameCursor::new(&mut b);
+
+        assert_eq!(cursor.next_frame(), Some(Ok(Frame::Ping)));
+        assert_eq!(cursor.next_frame(),
+                   Some(Err((Error::UnknownFrame(0x1f), 1))));
+        assert_eq!(cursor.next_frame(), None);
+    }
+
+    #[test]
+    fn reason_str() {
+        let frame = Frame::ConnectionClose {
+            error_code: 0xbeef,
+            frame_type: 523423,
+            reason: Cow::Borrowed(b"bad things"),
+        };
+
+        assert_eq!(frame.reason_str(), Some(Cow::Borrowed("bad things")));
+
+        let frame = Frame::ApplicationClose {
+            error_code: 0xbeef,
+            reason: Cow::Borrowed(&[b'b', b'a', 0xff, b'd']),
+        };
+
+        assert_eq!(frame.reason_str().unwrap(), "ba\u{fffd}d");
+
+        assert_eq!(Frame::Ping.reason_str(), None);
+    }
+
+    #[test]
+    fn transport_error() {
+        let frame = Frame::ConnectionClose {
+            error_code: 0xa,
+            frame_type: 523423,
+            reason: Cow::Borrowed(&[]),
+        };
+
+        assert_eq!(frame.transport_error(),
+                   Some(TransportError::ProtocolViolation));
+
+        let frame = Frame::ConnectionClose {
+            error_code: 0x12a,
+            frame_type: 0,
+            reason: Cow::Borrowed(&[]),
+        };
+
+        assert_eq!(frame.transport_error(),
+                   Some(TransportError::CryptoError(0x2a)));
+
+        let frame = Frame::ConnectionClose {
+            error_code: 0xbeef,
+            frame_type: 0,
+            reason: Cow::Borrowed(&[]),
+        };
+
+        assert_eq!(frame.transport_error(),
+                   Some(TransportError::Unknown(0xbeef)));
+
+        let frame = Frame::ApplicationClose {
+            error_code: 0xa,
+            reason: Cow::Borrowed(&[]),
+        };
+
+        assert_eq!(frame.transport_error(), None);
+
+        for code in 0..0x200 {
+            assert_eq!(TransportError::from_code(code).to_code(), code);
+        }
+
+        assert_eq!(TransportError::from_code(0xbeef).to_code(), 0xbeef);
+    }
+
+    #[test]
+    fn connection_close_triggered_by() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 3] = [1, 2, 3];
+
+        let offender = Frame::Stream {
+            stream_id: 4,
+            offset: 0,
+            data: Cow::Borrowed(&data),
+            fin: true,
+        };
+
+        let frame = Frame::connection_close(TransportError::FinalOffsetError,
+                                            Some(offender.ty()),
+                                            b"bad fin".to_vec());
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 12);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            let parsed = Frame::from_bytes(&mut b).unwrap();
+
+            match parsed {
+                Frame::ConnectionClose { error_code, frame_type, .. } => {
+                    assert_eq!(error_code, 0x6);
+                    assert_eq!(frame_type, 0x17);
+                },
+
+                _ => panic!("unexpected frame"),
+            }
+        }
+
+        let frame = Frame::connection_close(TransportError::InternalError,
+                                            None, Vec::new());
+
+        assert_eq!(frame, Frame::ConnectionClose {
+            error_code: 0x1,
+            frame_type: 0,
+            reason: Cow::Borrowed(&[]),
+        });
+    }
+
+    #[test]
+    fn into_owned() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let owned = {
+            let mut b = octets::Bytes::new(&mut d);
+            Frame::from_bytes(&mut b).unwrap().into_owned()
+        };
+
+        assert_eq!(owned, frame);
+    }
+
+    #[test]
+    fn ack_from_received() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let received: BTreeSet<u64> =
+            [0, 1, 2, 5, 6, 9].iter().cloned().collect();
+
+        let frame = Frame::ack_from_received(&received, 0x10).unwrap();
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(0..3);
+        ranges.insert_range(5..7);
+        ranges.insert_range(9..10);
+
+        assert_eq!(frame, Frame::ACK {
+            ack_delay: 0x10,
+            ranges,
+            ecn: None,
+        });
+
+        assert_eq!(frame.largest_acked(), Some(9));
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        // largest_ack, ack_delay, block_count, first_block, then the
+        // gap/ack_block pairs for 5..7 and 0..3.
+        assert_eq!(&d[..wire_len], [0x0d, 9, 0x10, 2, 0, 1, 1, 1, 2]);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn acked_packets() {
+        let received: BTreeSet<u64> = [0, 1, 2, 5, 6].iter().cloned().collect();
+
+        let frame = Frame::ack_from_received(&received, 0).unwrap();
+
+        let acked: Vec<u64> = frame.acked_packets().collect();
+        assert_eq!(acked, vec![6, 5, 2, 1, 0]);
+
+        let frame = ack_frame(2163721632, 0);
+
+        let acked: Vec<u64> = frame.acked_packets().collect();
+        assert_eq!(acked, vec![2163721632]);
+
+        let frame = Frame::ACK {
+            ack_delay: 0,
+            ranges: ranges::RangeSet::default(),
+            ecn: None,
+        };
+
+        assert_eq!(frame.acked_packets().count(), 0);
+
+        assert_eq!(Frame::Ping.acked_packets().count(), 0);
+    }
+
+    #[test]
+    fn clone() {
+        let mut d: [u8; 128] = [42; 128];
+        let mut out: [u8; 128] = [0; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        let owned = {
+            let mut b = octets::Bytes::new(&mut d);
+            let parsed = Frame::from_bytes(&mut b).unwrap();
+
+            let cloned = parsed.clone();
+            assert_eq!(cloned, parsed);
+
+            cloned.into_owned()
+        };
+
+        {
+            let mut b = octets::Bytes::new(&mut out);
+            assert_eq!(owned.to_bytes(&mut b).unwrap(), wire_len);
+        }
+
+        assert_eq!(&out[..wire_len], &d[..wire_len]);
+    }
+
+    #[test]
+    fn validate_payload_empty() {
+        let mut d: [u8; 0] = [];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(validate_payload(&mut b), Err(Error::InvalidPacket));
+    }
+
+    #[test]
+    fn validate_payload_well_formed() {
+        let mut d: [u8; 128] = [0; 128];
+
+        let data: [u8; 3] = [1, 2, 3];
+
+        let frames = vec![
+            Frame::Ping,
+            Frame::Stream {
+                stream_id: 4,
+                offset: 0,
+                data: Cow::Borrowed(&data),
+                fin: false,
+            },
+            Frame::Padding { len: 2 },
+        ];
+
+        let len = {
+            let mut b = octets::Bytes::new(&mut d);
+
+            let mut len = 0;
+            for f in &frames {
+                len += f.to_bytes(&mut b).unwrap();
+            }
+
+            len
+        };
+
+        let mut b = octets::Bytes::new(&mut d[..len]);
+        assert_eq!(validate_payload(&mut b).unwrap(), frames);
+    }
+
+    #[test]
+    fn validate_payload_trailing_garbage() {
+        let mut d: [u8; 128] = [0; 128];
+
+        let len = {
+            let mut b = octets::Bytes::new(&mut d);
+
+            Frame::Ping.to_bytes(&mut b).unwrap()
+        };
+
+        // Unknown frame type followed by a stray byte.
+        d[len] = 0x3f;
+        d[len + 1] = 0xff;
+        let len = len + 2;
+
+        let mut b = octets::Bytes::new(&mut d[..len]);
+        assert!(validate_payload(&mut b).is_err());
+    }
+
+    #[test]
+    fn ack_first_block_underflow() {
+        // largest_ack = 5, ack_delay = 0, block_count = 0, first_block = 10
+        let mut d: [u8; 5] = [0x0d, 0x05, 0x00, 0x00, 0x0a];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::InvalidFrame));
+
+        let mut d: [u8; 5] = [0x0d, 0x05, 0x00, 0x00, 0x05];
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(0..6);
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Ok(Frame::ACK {
+            ack_delay: 0,
+            ranges,
+            ecn: None,
+        }));
+    }
+
+    #[test]
+    fn stream_fixed_len_width() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes_with_stream_len_width(&mut b, 4).unwrap()
+        };
+
+        assert_eq!(wire_len, frame.wire_len() + 3);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn packet_in_flight_ack_only() {
+        let frames = vec![
+            ack_frame(2163721632, 874656534),
+        ];
+
+        assert!(!packet_in_flight(&frames));
+    }
+
+    #[test]
+    fn packet_in_flight_padding_only() {
+        let frames = vec![Frame::Padding { len: 2 }];
+
+        assert!(packet_in_flight(&frames));
+    }
+
+    #[test]
+    fn packet_in_flight_stream() {
+        let data: [u8; 3] = [1, 2, 3];
+
+        let frames = vec![
+            ack_frame(2163721632, 874656534),
+            Frame::Stream {
+                stream_id: 4,
+                offset: 0,
+                data: Cow::Borrowed(&data),
+                fin: false,
+            },
+        ];
+
+        assert!(packet_in_flight(&frames));
+    }
+
+    #[test]
+    fn stream_truncated_data() {
+        let mut d: [u8; 128] = [42; 128];
+
+        // STREAM frame with offset and length, declaring 100 bytes of data
+        // for stream 4 but followed by only 50.
+        d[0] = 0x16;
+        d[1] = 0x04;
+        d[2] = 0x00;
+        d[3] = 0x40;
+        d[4] = 100;
+
+        {
+            let mut b = octets::Bytes::new(&mut d[5..55]);
+            assert_eq!(b.get_bytes(100).err(), Some(Error::BufferTooShort));
+        }
+
+        let mut b = octets::Bytes::new(&mut d[..55]);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::TruncatedFrame {
+            declared: 100,
+            available: 50,
+        }));
+    }
+
+    #[test]
+    fn ack_capped() {
+        let mut d: [u8; 256] = [42; 256];
+
+        let mut ranges = ranges::RangeSet::new();
+
+        for i in 0..100 {
+            ranges.insert_range(i * 10..i * 10 + 3);
+        }
+
+        let (wire_len, lowest) = {
+            let mut b = octets::Bytes::new(&mut d);
+            write_ack_capped(&ranges, 0, None, &mut b, 20).unwrap()
+        };
+
+        assert_eq!(wire_len, 20);
+        assert_eq!(lowest, 920);
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            let frame = Frame::from_bytes(&mut b).unwrap();
+            assert_eq!(frame.largest_acked(), Some(992));
+
+            match frame {
+                Frame::ACK { ranges: acked, .. } => {
+                    assert_eq!(acked.len(), 8);
+                    assert_eq!(acked.smallest(), Some(lowest));
+                },
+
+                _ => panic!("unexpected frame"),
+            }
+        }
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            Frame::from_bytes(&mut b).unwrap();
+            assert_eq!(b.cap(), 0);
+        }
+
+        let (wire_len, lowest) = {
+            let mut b = octets::Bytes::new(&mut d);
+            write_ack_capped(&ranges, 0, None, &mut b, 256).unwrap()
+        };
+
+        assert_eq!(wire_len, 205);
+        assert_eq!(lowest, 0);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert!(write_ack_capped(&ranges, 0, None, &mut b, 5).is_err());
+        }
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(write_ack_capped(&ranges::RangeSet::new(), 0, None, &mut b,
+                                    20),
+                   Err(Error::InvalidFrame));
+    }
+
+    #[test]
+    fn stream_eq_buffer_size_independent() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Stream {
+            stream_id: 32,
+            offset: 1230976,
+            data: Cow::Borrowed(&data),
+            fin: true
+        };
+
+        let mut small: [u8; 32] = [0; 32];
+        let mut large: [u8; 1024] = [0xff; 1024];
+
+        {
+            let mut b = octets::Bytes::new(&mut small);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        {
+            let mut b = octets::Bytes::new(&mut large);
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let mut b1 = octets::Bytes::new(&mut small);
+        let mut b2 = octets::Bytes::new(&mut large);
+
+        let f1 = Frame::from_bytes(&mut b1).unwrap();
+        let f2 = Frame::from_bytes(&mut b2).unwrap();
+
+        assert_eq!(f1, f2);
+        assert_eq!(f1, frame);
+    }
+
+    #[test]
+    fn keepalive() {
+        let frames = keepalive_frames(None);
+
+        assert_eq!(frames, vec![Frame::Ping]);
+        assert!(packet_in_flight(&frames));
+    }
+
+    #[test]
+    fn keepalive_padded() {
+        let frames = keepalive_frames(Some(1200));
+
+        assert_eq!(frames, vec![Frame::Ping, Frame::Padding { len: 1199 }]);
+
+        let wire_len: usize = frames.iter().map(|f| f.wire_len()).sum();
+        assert_eq!(wire_len, 1200);
+
+        assert!(packet_in_flight(&frames));
+    }
+
+    #[test]
+    fn ack_max_blocks() {
+        let mut d: [u8; 1200] = [42; 1200];
+
+        let mut ranges = ranges::RangeSet::new();
+
+        for i in 0..1000 {
+            ranges.insert_range(i * 1000..i * 1000 + 10);
+        }
+
+        assert_eq!(ranges.len(), 1000);
+
+        let (wire_len, lowest) = {
+            let mut b = octets::Bytes::new(&mut d);
+            write_ack_capped(&ranges, 0, None, &mut b, 1200).unwrap()
+        };
+
+        // The type, largest_ack, ack_delay, block_count and first_block
+        // take 9 bytes, and each further range 3 (a 2-byte gap and a 1-byte
+        // block), so only the 398 highest ranges fit.
+        assert_eq!(wire_len, 1200);
+        assert_eq!(lowest, 602_000);
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            let frame = Frame::from_bytes(&mut b).unwrap();
+            assert_eq!(frame.largest_acked(), Some(999_009));
+
+            match frame {
+                Frame::ACK { ranges: acked, .. } => {
+                    assert_eq!(acked.len(), 398);
+                    assert_eq!(acked.largest(), ranges.largest());
+                    assert_eq!(acked.smallest(), Some(602_000));
+                },
+
+                _ => panic!("unexpected frame"),
+            }
+        }
+    }
+
+    #[test]
+    fn ack_block_count_too_large() {
+        // largest_ack = 5, ack_delay = 0, block_count = 16383,
+        // first_block = 0, followed by a single block.
+        let mut d: [u8; 8] = [0x0d, 0x05, 0x00, 0x7f, 0xff, 0x00, 0x00, 0x00];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn truncated() {
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(4..7);
+        ranges.insert_range(9..12);
+
+        let frames = vec![
+            Frame::Stream {
+                stream_id: 32,
+                offset: 1230976,
+                data: Cow::Borrowed(&data),
+                fin: true,
+            },
+            Frame::Crypto {
+                offset: 1230976,
+                data: Cow::Borrowed(&data),
+            },
+            Frame::ACK {
+                ack_delay: 874656534,
+                ranges,
+                ecn: Some(EcnCounts { ect0: 1, ect1: 2, ce: 3 }),
+            },
+            Frame::ConnectionClose {
+                error_code: 0xbeef,
+                frame_type: 523423,
+                reason: Cow::Borrowed(&[1, 2, 3]),
+            },
+            Frame::NewConnectionId {
+                seq_num: 123213,
+                conn_id: Cow::Borrowed(&[1, 2, 3, 4]),
+                reset_token: Cow::Borrowed(&[0x42; 16]),
+            },
+            Frame::PathChallenge { data: [1, 2, 3, 4, 5, 6, 7, 8] },
+            Frame::Datagram { data: Cow::Borrowed(&data) },
+        ];
+
+        for frame in &frames {
+            let mut d: [u8; 128] = [42; 128];
+
+            let wire_len = {
+                let mut b = octets::Bytes::new(&mut d);
+                frame.to_bytes(&mut b).unwrap()
+            };
+
+            // A frame cut short anywhere means more bytes are needed, not
+            // that the frame is invalid. Cutting into the data of a STREAM
+            // frame also reports how much of it is missing.
+            for len in 1..wire_len {
+                let err = match frame {
+                    Frame::Stream { data, .. }
+                        if len >= wire_len - data.len() =>
+                            Error::TruncatedFrame {
+                                declared: data.len(),
+                                available: len - (wire_len - data.len()),
+                            },
+
+                    _ => Error::BufferTooShort,
+                };
+
+                let mut b = octets::Bytes::new(&mut d[..len]);
+                assert_eq!(Frame::from_bytes(&mut b), Err(err),
+                           "{:?} truncated to {}", frame, len);
+            }
+        }
+    }
+
+    #[test]
+    fn reset_stream() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::ResetStream {
+            stream_id: 123213,
+            error_code: 15352,
+            final_size: 21123,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 11);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn unknown_ext() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::UnknownExt {
+            frame_type: 0x3f00,
+            raw: vec![1, 2, 3, 4, 5],
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 8);
+        assert_eq!(wire_len, frame.wire_len());
+        assert_eq!(d[0], frame.first_byte());
+        assert_eq!(&d[..wire_len], &[0x7f, 0x00, 0x05, 1, 2, 3, 4, 5]);
+
+        let mut out: [u8; 128] = [0; 128];
+
+        let fwd_len = {
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            let parsed = Frame::from_bytes(&mut b).unwrap();
+            assert_eq!(parsed, frame);
+
+            let mut b = octets::Bytes::new(&mut out);
+            parsed.to_bytes(&mut b).unwrap()
+        };
+
+        // Forwarded verbatim.
+        assert_eq!(&out[..fwd_len], &d[..wire_len]);
+    }
+
+    #[test]
+    fn unknown_ext_unregistered() {
+        let mut d: [u8; 8] = [0x7f, 0x01, 0x05, 1, 2, 3, 4, 5];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::UnknownFrame(0x3f01)));
+
+        assert_eq!(FrameOwned::unknown_ext(0x3f00, vec![1, 2, 3]),
+                   Ok(Frame::UnknownExt {
+                       frame_type: 0x3f00,
+                       raw: vec![1, 2, 3],
+                   }));
+
+        // A known type would be re-encoded as a different frame.
+        assert_eq!(FrameOwned::unknown_ext(0x07, vec![]),
+                   Err(Error::UnknownFrame(0x07)));
+
+        // Frames built without the constructor are checked when encoding,
+        // before anything is written.
+        for &frame_type in [0x07, 0x3f01, u64::MAX].iter() {
+            let frame = Frame::UnknownExt {
+                frame_type,
+                raw: vec![1, 2, 3],
+            };
+
+            let mut d: [u8; 128] = [42; 128];
+
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(frame.to_bytes(&mut b),
+                       Err(Error::UnknownFrame(frame_type)));
+            assert_eq!(b.off(), 0);
+
+            // Doesn't panic.
+            frames_digest(&[frame]);
+        }
+    }
+
+    #[test]
+    fn stop_sending() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::StopSending {
+            stream_id: 123213,
+            error_code: 15352,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 7);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn max_data() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::MaxData {
+            max: 128318273,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 5);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn max_stream_data() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::MaxStreamData {
+            stream_id: (1 << 62) - 4,
+            max: 128318273,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 13);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn max_stream_id() {
+        let mut d: [u8; 128] = [42; 128];
+
+        // The direction is encoded in the stream ID itself, so check both a
+        // bidirectional and a unidirectional one.
+        for max in [128318272, 128318274].iter() {
+            let frame = Frame::MaxStreamId {
+                max: *max,
+            };
+
+            let wire_len = {
+                let mut b = octets::Bytes::new(&mut d);
+                frame.to_bytes(&mut b).unwrap()
+            };
+
+            assert_eq!(wire_len, 5);
+            assert_eq!(wire_len, frame.wire_len());
+
+            {
+                let mut b = octets::Bytes::new(&mut d);
+                assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+            }
+        }
+    }
+
+    #[test]
+    fn data_blocked() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::DataBlocked {
+            limit: 128318273,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 5);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn stream_data_blocked() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::StreamDataBlocked {
+            stream_id: 12321,
+            limit: 128318273,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 7);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn stream_id_blocked() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::StreamIdBlocked {
+            limit: 128318273,
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 5);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn path_challenge() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::PathChallenge {
+            data: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 9);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn path_response() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::PathResponse {
+            data: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 9);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn handshake_done() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::HandshakeDone;
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 1);
+        assert_eq!(wire_len, frame.wire_len());
+        assert_eq!(&d[..wire_len], [0x1e]);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn new_token() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::NewToken {
+            token: Cow::Borrowed(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 14);
+        assert_eq!(wire_len, frame.wire_len());
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn new_token_empty() {
+        let mut d: [u8; 2] = [0x19, 0x00];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::InvalidToken));
+    }
+
+    #[test]
+    fn ack_gap_underflow() {
+        // largest_ack = 5, ack_delay = 0, block_count = 1, first_block = 0,
+        // gap = 4, ack_block = 0
+        let mut d: [u8; 7] = [0x0d, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Err(Error::InvalidFrame));
+
+        // gap = 3 leaves packet 0 as the only one in the second block.
+        let mut d: [u8; 7] = [0x0d, 0x05, 0x00, 0x01, 0x00, 0x03, 0x00];
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(0).unwrap();
+        ranges.insert(5).unwrap();
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b), Ok(Frame::ACK {
+            ack_delay: 0,
+            ranges,
+            ecn: None,
+        }));
+    }
+
+    #[test]
+    fn ack_delay_exponent() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = Frame::ack(ranges::RangeSet::default(), 25000, 3);
+        assert_eq!(frame.ack_delay_micros(3), 25000);
+
+        match frame {
+            Frame::ACK { ack_delay, .. } => assert_eq!(ack_delay, 3125),
+
+            _ => panic!("unexpected frame"),
+        }
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(1234).unwrap();
+
+        // Precision below the exponent's granularity is lost.
+        let frame = Frame::ack(ranges, 25001, 3);
+        assert_eq!(frame.ack_delay_micros(3), 25000);
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            let parsed = Frame::from_bytes(&mut b).unwrap();
+            assert_eq!(parsed, frame);
+            assert_eq!(parsed.ack_delay_micros(3), 25000);
+        }
+
+        assert_eq!(Frame::Ping.ack_delay_micros(3), 0);
+    }
+
+    #[test]
+    fn ack_ecn() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(4..7);
+        ranges.insert_range(9..12);
+        ranges.insert_range(15..19);
+        ranges.insert_range(3000..5000);
+
+        let frame = Frame::ACK {
+            ack_delay: 874656534,
+            ranges,
+            ecn: Some(EcnCounts {
+                ect0: 100,
+                ect1: 0,
+                ce: 23,
+            }),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 21);
+        assert_eq!(wire_len, frame.wire_len());
+        assert_eq!(d[0], 0x1a);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn datagram() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Datagram {
+            data: Cow::Borrowed(&data),
+        };
+
+        let wire_len = {
+            let mut b = octets::Bytes::new(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 14);
+        assert_eq!(wire_len, frame.wire_len());
+        assert_eq!(d[0], 0x31);
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn datagram_implicit_len() {
+        let mut d: [u8; 13] = [0x30, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let frame = Frame::Datagram {
+            data: Cow::Borrowed(&data),
+        };
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        assert_eq!(b.cap(), 0);
+    }
+
+    #[test]
+    fn frame_type_roundtrip() {
+        for v in 0..0x40 {
+            if let Ok(ty) = FrameType::try_from(v) {
+                assert_eq!(u64::from(ty), v);
+            }
+        }
+
+        assert_eq!(FrameType::try_from(0x16), Ok(FrameType::Stream(0x06)));
+        assert_eq!(FrameType::try_from(0x3f00),
+                   Ok(FrameType::Extension(0x3f00)));
+
+        assert_eq!(FrameType::try_from(0x1f), Err(Error::UnknownFrame(0x1f)));
+        assert_eq!(FrameType::try_from(0x3f01),
+                   Err(Error::UnknownFrame(0x3f01)));
+    }
+
+    #[test]
+    fn frame_type_matches_encoding() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 3] = [1, 2, 3];
+
+        let frames = vec![
+            Frame::Ping,
+            ack_frame(2163721632, 874656534),
+            Frame::Stream {
+                stream_id: 4,
+                offset: 0,
+                data: Cow::Borrowed(&data),
+                fin: false,
+            },
+            Frame::Datagram {
+                data: Cow::Borrowed(&data),
+            },
+        ];
+
+        for frame in &frames {
+            {
+                let mut b = octets::Bytes::new(&mut d);
+                frame.to_bytes(&mut b).unwrap();
+            }
+
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(b.get_varint().unwrap(), frame.frame_type());
+        }
+    }
+
+    #[test]
+    fn ack_eliciting() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        for kind in 0..ARBITRARY_FRAME_KINDS {
+            let frame = arbitrary_frame(&mut rng, kind);
+
+            let eliciting = !matches!(frame, Frame::Padding { .. } |
+                                             Frame::ConnectionClose { .. } |
+                                             Frame::ApplicationClose { .. } |
+                                             Frame::ACK { .. });
+
+            assert_eq!(frame.is_ack_eliciting(), eliciting, "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn retransmittable() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        for kind in 0..ARBITRARY_FRAME_KINDS {
+            let frame = arbitrary_frame(&mut rng, kind);
+
+            let retransmittable = !matches!(frame,
+                                            Frame::Padding { .. } |
+                                            Frame::Ping |
+                                            Frame::ACK { .. } |
+                                            Frame::ConnectionClose { .. } |
+                                            Frame::ApplicationClose { .. } |
+                                            Frame::PathChallenge { .. } |
+                                            Frame::PathResponse { .. } |
+                                            Frame::Datagram { .. } |
+                                            Frame::UnknownExt { .. });
+
+            assert_eq!(frame.is_retransmittable(), retransmittable,
+                       "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn parse_all() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let data: [u8; 3] = [1, 2, 3];
+
+        let frames = vec![
+            Frame::Ping,
+            Frame::Padding { len: 2 },
+            Frame::Stream {
+                stream_id: 4,
+                offset: 0,
+                data: Cow::Borrowed(&data),
+                fin: false,
+            },
+        ];
+
+        let len = {
+            let mut b = octets::Bytes::new(&mut d);
+
+            let mut len = 0;
+            for f in &frames {
+                len += f.to_bytes(&mut b).unwrap();
+            }
+
+            len
+        };
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..len]);
+            assert_eq!(Frame::parse_all(&mut b).unwrap(), frames);
+            assert_eq!(b.cap(), 0);
+        }
+
+        {
+            let mut b = octets::Bytes::new(&mut d[..0]);
+            assert_eq!(Frame::parse_all(&mut b).unwrap(), vec![]);
+        }
+
+        // Truncated STREAM frame.
+        let mut b = octets::Bytes::new(&mut d[..len - 1]);
+        assert_eq!(Frame::parse_all(&mut b), Err((Error::TruncatedFrame {
+            declared: 3,
+            available: 2,
+        }, 3)));
+    }
+
+    #[test]
+    fn ecn_counts() {
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(0..10);
+
+        let frame = Frame::ACK {
+            ack_delay: 0,
+            ranges,
+            ecn: Some(EcnCounts {
+                ect0: 6,
+                ect1: 1,
+                ce: 3,
+            }),
+        };
+
+        let prev = EcnCounts::default();
+
+        assert_eq!(validate_ecn_counts(&frame, &prev, 10), Ok(()));
+        assert_eq!(validate_ecn_counts(&frame, &prev, 9),
+                   Err(Error::InvalidFrame));
+
+        // Only the increase since the previous frame counts.
+        let prev = EcnCounts {
+            ect0: 4,
+            ect1: 1,
+            ce: 0,
+        };
+
+        assert_eq!(validate_ecn_counts(&frame, &prev, 5), Ok(()));
+        assert_eq!(validate_ecn_counts(&frame, &prev, 4),
+                   Err(Error::InvalidFrame));
+
+        // Counts going backwards.
+        let prev = EcnCounts {
+            ect0: 7,
+            ect1: 0,
+            ce: 0,
+        };
+
+        assert_eq!(validate_ecn_counts(&frame, &prev, 10),
+                   Err(Error::InvalidFrame));
+
+        // Nothing to check without ECN counts.
+        let frame = ack_frame(9, 0);
+        assert_eq!(validate_ecn_counts(&frame, &prev, 0), Ok(()));
+    }
+
+    #[test]
+    fn ack_ecn_counts_only() {
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(4..7);
+        ranges.insert_range(9..12);
+
+        let first = Frame::ACK {
+            ack_delay: 874656534,
+            ranges: ranges.clone(),
+            ecn: Some(EcnCounts {
+                ect0: 100,
+                ect1: 0,
+                ce: 23,
+            }),
+        };
+
+        let second = Frame::ACK {
+            ack_delay: 874656534,
+            ranges,
+            ecn: Some(EcnCounts {
+                ect0: 100,
+                ect1: 0,
+                ce: 24,
+            }),
+        };
+
+        assert_ne!(first, second);
+
+        for frame in &[first, second] {
+            let mut d: [u8; 128] = [42; 128];
+
+            let wire_len = {
+                let mut b = octets::Bytes::new(&mut d);
+                frame.to_bytes(&mut b).unwrap()
+            };
+
+            assert_eq!(wire_len, frame.wire_len());
+
+            let mut b = octets::Bytes::new(&mut d[..wire_len]);
+            assert_eq!(&Frame::from_bytes(&mut b).unwrap(), frame);
+        }
+    }
+
+    // Values at the edges of each varint size, along with their encoded
+    // length.
+    const VARINT_BOUNDARIES: [(u64, usize); 8] = [
+        (0, 1),
+        (63, 1),
+        (64, 2),
+        (16383, 2),
+        (16384, 4),
+        (1_073_741_823, 4),
+        (1_073_741_824, 8),
+        (4_611_686_018_427_387_903, 8),
+    ];
+
+    // Generates a test that round-trips a frame with a single varint field
+    // set to each of the VARINT_BOUNDARIES values.
+    macro_rules! varint_boundaries_test {
+        ($name:ident, $variant:ident, $field:ident) => {
+            #[test]
+            fn $name() {
+                for &(v, len) in VARINT_BOUNDARIES.iter() {
+                    let mut d: [u8; 128] = [42; 128];
+
+                    let frame = Frame::$variant { $field: v };
+
+                    let wire_len = {
+                        let mut b = octets::Bytes::new(&mut d);
+                        frame.to_bytes(&mut b).unwrap()
+                    };
+
+                    assert_eq!(wire_len, 1 + len);
+                    assert_eq!(frame.wire_len(), 1 + len);
+
+                    let mut b = octets::Bytes::new(&mut d[..wire_len]);
+                    assert_eq!(Frame::from_bytes_strict(&mut b).unwrap(),
+                               frame);
+                }
+            }
+        };
+    }
+
+    varint_boundaries_test!(max_data_varint_boundaries, MaxData, max);
+
+    varint_boundaries_test!(max_stream_id_varint_boundaries, MaxStreamId, max);
+
+    varint_boundaries_test!(data_blocked_varint_boundaries, DataBlocked, limit);
+
+    varint_boundaries_test!(stream_id_blocked_varint_boundaries,
+                            StreamIdBlocked, limit);
+
+    #[test]
+    fn newly_acked_spurious_retransmit() {
+        // Packets 0-9 were sent, and 0-4 and 8-9 were acknowledged. Packets
+        // 5-7 were then declared lost and retransmitted as 10-12.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert_range(0..5);
+        acked.insert_range(8..10);
+
+        // The original packet 6 turns out to have arrived after all, along
+        // with the retransmissions, so its loss was spurious.
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert_range(0..5);
+        ranges.insert(6).unwrap();
+        ranges.insert_range(8..13);
+
+        let frame = Frame::ACK {
+            ack_delay: 0,
+            ranges,
+            ecn: None,
+        };
+
+        let new = newly_acked(&acked, &frame);
+        assert_eq!(new.iter().cloned().collect::<Vec<_>>(), vec![6..7, 10..13]);
+
+        // Of the packets declared lost, only 6 is newly acknowledged.
+        let spurious: Vec<u64> =
+            (5..8).filter(|pn| new.contains(*pn)).collect();
+        assert_eq!(spurious, vec![6]);
+
+        // Nothing new once everything is known to be acknowledged.
+        acked.insert_range(6..7);
+        acked.insert_range(10..13);
+        assert!(newly_acked(&acked, &frame).is_empty());
+
+        assert!(newly_acked(&acked, &Frame::Ping).is_empty());
+    }
+
+    #[test]
+    fn prelude_classify_packet() {
+        use frame::prelude;
+
+        // A PMTU probe.
+        let probe = vec![
+            prelude::Frame::ping(),
+            prelude::FrameOwned::padding(100),
+        ];
+
+        // An ACK-only packet.
+        let ack = vec![ack_frame(42, 0), prelude::FrameOwned::padding(10)];
+
+        // A packet carrying stream data, along with an ACK.
+        let data = vec![
+            ack_frame(42, 0),
+            prelude::FrameOwned::stream(4, 0, b"hello".to_vec(), true),
+        ];
 
-            Frame::NewConnectionId { seq_num, conn_id, reset_token } => {
-                1 +                                // frame type
-                octets::varint_len(*seq_num) +     // seq_num
-                1 +                                // conn_id length
-                conn_id.len() +                    // conn_id
-                reset_token.len()                  // reset_token
-            },
+        let classify = |frames: &[FrameOwned]| {
+            let mut payload = Vec::new();
 
-            Frame::ACK { largest_ack, ack_delay } => {
-                1 +                                // frame type
-                octets::varint_len(*largest_ack) + // largest_ack
-                octets::varint_len(*ack_delay) +   // ack_delay
-                1 +                                // block_count
-                1                                  // first_block
+            for f in frames {
+                f.append_to_vec(&mut payload).unwrap();
             }
 
-            Frame::Crypto { offset, data } => {
-                1 +                              // frame type
-                octets::varint_len(*offset) +    // offset
-                octets::varint_len(data.cap() as u64) + // length
-                data.cap()                       // data
-            }
+            let mut b = octets::Bytes::new(&mut payload);
+            let frames = prelude::Frame::parse_all(&mut b).unwrap();
 
-            Frame::Stream { stream_id, offset, data, .. } => {
-                1 +                              // frame type
-                octets::varint_len(*stream_id) + // stream_id
-                octets::varint_len(*offset) +    // offset
-                octets::varint_len(data.cap() as u64) + // length
-                data.cap()                       // data
-            }
-        }
-    }
-}
+            (prelude::ack_eliciting(&frames), prelude::retransmittable(&frames))
+        };
 
-fn parse_ack_frame<'a>(_ty: u64, b: &mut octets::Bytes) -> Result<Frame<'a>> {
-    let largest_ack = b.get_varint()?;
-    let ack_delay = b.get_varint()?;
-    let block_count = b.get_varint()?;
-    let _first_block = b.get_varint()?;
+        assert_eq!(classify(&probe), (true, false));
+        assert_eq!(classify(&ack), (false, false));
+        assert_eq!(classify(&data), (true, true));
 
-    // TODO: properly store ACK blocks
-    for _i in 0..block_count {
-        let _gap = b.get_varint()?;
-        let _ack = b.get_varint()?;
+        assert!(!prelude::ack_eliciting(&[]));
+        assert!(!prelude::retransmittable(&[]));
     }
 
-    Ok(Frame::ACK {
-        largest_ack,
-        ack_delay,
-    })
-}
-
-fn parse_stream_frame<'a>(ty: u64, b: &'a mut octets::Bytes) -> Result<Frame<'a>> {
-    let first = ty as u8;
+    #[test]
+    fn ack_from_received_max() {
+        let received: BTreeSet<u64> =
+            [0, u64::MAX].iter().cloned().collect();
 
-    let stream_id = b.get_varint()?;
+        assert_eq!(Frame::ack_from_received(&received, 0),
+                   Err(Error::ValueOutOfRange));
+    }
 
-    let offset = if first & 0x04 != 0 {
-        b.get_varint()?
-    } else {
-        0
-    };
+    #[test]
+    fn parse_error_offset() {
+        // PING, 3 bytes of PADDING, then MAX_DATA with a truncated value.
+        let mut d: [u8; 6] = [0x07, 0x00, 0x00, 0x00, 0x04, 0x40];
 
-    let len = if first & 0x02 != 0 {
-        b.get_varint()? as usize
-    } else {
-        b.cap()
-    };
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::parse_all(&mut b),
+                       Err((Error::BufferTooShort, 4)));
+        }
 
-    let fin = first & 0x01 != 0;
+        let mut b = octets::Bytes::new(&mut d);
+        let mut cursor = FrameCursor::new(&mut b);
 
-    let data = b.get_bytes(len)?;
+        assert_eq!(cursor.next_frame(), Some(Ok(Frame::Ping)));
+        assert_eq!(cursor.next_frame(), Some(Ok(Frame::Padding { len: 3 })));
+        assert_eq!(cursor.next_frame(), Some(Err((Error::BufferTooShort, 4))));
+        assert_eq!(cursor.next_frame(), None);
+    }
 
-    Ok(Frame::Stream {
-        stream_id,
-        offset,
-        data,
-        fin,
-    })
-}
+    #[test]
+    fn strict_varints() {
+        // MAX_DATA with a 2-byte encoding of 5.
+        let mut d: [u8; 3] = [0x04, 0x40, 0x05];
 
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(),
+                       Frame::MaxData { max: 5 });
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes_strict(&mut b),
+                       Err(Error::InvalidVarint));
+        }
 
-    #[test]
-    fn padding() {
-        let mut d: [u8; 128] = [42; 128];
+        // Non-minimal frame type.
+        let mut d: [u8; 2] = [0x40, 0x07];
 
-        let frame = Frame::Padding;
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes(&mut b).unwrap(), Frame::Ping);
+        }
 
-        let wire_len = {
+        {
             let mut b = octets::Bytes::new(&mut d);
-            frame.to_bytes(&mut b).unwrap()
-        };
+            assert_eq!(Frame::from_bytes_strict(&mut b),
+                       Err(Error::InvalidVarint));
+        }
 
-        assert_eq!(wire_len, 1);
-        assert_eq!(&d[..wire_len], [0 as u8]);
+        // CRYPTO with a non-minimal length.
+        let mut d: [u8; 6] = [0x18, 0x00, 0x40, 0x02, 0x01, 0x02];
 
         {
             let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+            assert!(Frame::from_bytes(&mut b).is_ok());
+        }
+
+        {
+            let mut b = octets::Bytes::new(&mut d);
+            assert_eq!(Frame::from_bytes_strict(&mut b),
+                       Err(Error::InvalidVarint));
         }
+
+        // Minimal encodings are accepted either way.
+        let mut d: [u8; 7] = [0x18, 0x00, 0x02, 0x01, 0x02, 0x04, 0x05];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert!(Frame::from_bytes_strict(&mut b).is_ok());
+        assert_eq!(Frame::from_bytes_strict(&mut b).unwrap(),
+                   Frame::MaxData { max: 5 });
     }
 
     #[test]
-    fn connection_close() {
-        let mut d: [u8; 128] = [42; 128];
+    fn to_bytes_too_short() {
+        let mut d: [u8; 4] = [42; 4];
 
-        let frame = Frame::ConnectionClose {
-            error_code: 0xbeef,
-            frame_type: 523423,
-            reason: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
-        };
+        let data: [u8; 3] = [1, 2, 3];
 
-        let wire_len = {
-            let mut b = octets::Bytes::new(&mut d);
-            frame.to_bytes(&mut b).unwrap()
+        let frame = Frame::Stream {
+            stream_id: 4,
+            offset: 0,
+            data: Cow::Borrowed(&data),
+            fin: false,
         };
 
-        assert_eq!(wire_len, 20);
-
         {
             let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+            assert_eq!(frame.to_bytes(&mut b), Err(Error::BufferTooShort));
+            assert_eq!(b.off(), 0);
+
+            assert_eq!(frame.to_bytes_with_stream_len_width(&mut b, 2),
+                       Err(Error::BufferTooShort));
+            assert_eq!(b.off(), 0);
         }
+
+        assert_eq!(d, [42; 4]);
+
+        // An exact fit still works.
+        let mut d: [u8; 7] = [42; 7];
+
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(frame.to_bytes(&mut b).unwrap(), 7);
+        assert_eq!(b.cap(), 0);
     }
 
     #[test]
-    fn application_close() {
-        let mut d: [u8; 128] = [42; 128];
+    fn truncated_to() {
+        let data = vec![42; 100];
 
-        let frame = Frame::ApplicationClose {
-            error_code: 0xbeef,
-            reason: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+        let frame = Frame::Stream {
+            stream_id: 4,
+            offset: 1000,
+            data: Cow::Borrowed(&data),
+            fin: true,
         };
 
-        let wire_len = {
-            let mut b = octets::Bytes::new(&mut d);
-            frame.to_bytes(&mut b).unwrap()
+        // frame type + stream_id + offset (2 bytes) + length (2 bytes)
+        assert_eq!(frame.wire_len(), 106);
+        assert!(frame.fits_in(106));
+        assert!(!frame.fits_in(105));
+
+        assert_eq!(frame.truncated_to(106).unwrap(), frame);
+        assert_eq!(frame.truncated_to(200).unwrap(), frame);
+
+        let truncated = frame.truncated_to(105).unwrap();
+        assert_eq!(truncated, Frame::Stream {
+            stream_id: 4,
+            offset: 1000,
+            data: Cow::Borrowed(&data[..99]),
+            fin: false,
+        });
+        assert_eq!(truncated.wire_len(), 105);
+
+        // The length is encoded in a single byte when 63 bytes of data or
+        // less are left, so there's a byte to spare.
+        let truncated = frame.truncated_to(69).unwrap();
+        assert_eq!(truncated.wire_len(), 68);
+
+        let truncated = frame.truncated_to(30).unwrap();
+        assert_eq!(truncated.wire_len(), 30);
+        assert!(!truncated.fits_in(29));
+
+        let mut d: [u8; 30] = [0; 30];
+        let mut b = octets::Bytes::new(&mut d);
+        assert_eq!(truncated.to_bytes(&mut b).unwrap(), 30);
+
+        assert_eq!(frame.truncated_to(5).unwrap(), Frame::Stream {
+            stream_id: 4,
+            offset: 1000,
+            data: Cow::Borrowed(&[]),
+            fin: false,
+        });
+        assert_eq!(frame.truncated_to(4), None);
+
+        let frame = Frame::Crypto {
+            offset: 0,
+            data: Cow::Borrowed(&data),
         };
 
-        assert_eq!(wire_len, 16);
+        assert_eq!(frame.truncated_to(13).unwrap(), Frame::Crypto {
+            offset: 0,
+            data: Cow::Borrowed(&data[..10]),
+        });
+
+        let frame = Frame::Datagram {
+            data: Cow::Borrowed(&data),
+        };
+
+        assert_eq!(frame.truncated_to(103).unwrap(), frame);
+        assert_eq!(frame.truncated_to(102), None);
+
+        assert!(Frame::Ping.fits_in(1));
+        assert_eq!(Frame::Ping.truncated_to(100), None);
+    }
+
+    #[test]
+    fn arbitrary_roundtrip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        for i in 0..ARBITRARY_FRAME_KINDS * 500 {
+            let frame = arbitrary_frame(&mut rng, i % ARBITRARY_FRAME_KINDS);
+
+            let mut d = Vec::new();
+
+            let wire_len = frame.append_to_vec(&mut d).unwrap();
+            assert_eq!(wire_len, frame.wire_len(), "{:?}", frame);
 
-        {
             let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+            assert_eq!(Frame::from_bytes_strict(&mut b).unwrap(), frame);
+            assert_eq!(b.cap(), 0);
         }
     }
 
+    #[cfg(feature = "serde")]
     #[test]
-    fn ping() {
-        let mut d: [u8; 128] = [42; 128];
+    fn serde_roundtrip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
 
-        let frame = Frame::Ping;
+        for kind in 0..ARBITRARY_FRAME_KINDS {
+            let frame = arbitrary_frame(&mut rng, kind);
 
-        let wire_len = {
-            let mut b = octets::Bytes::new(&mut d);
-            frame.to_bytes(&mut b).unwrap()
+            let json = serde_json::to_string(&frame).unwrap();
+
+            let parsed: FrameOwned = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, frame, "{}", json);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_hex() {
+        let frame = Frame::Stream {
+            stream_id: 4,
+            offset: 0,
+            data: Cow::Borrowed(&b"\x00\xffhi"[..]),
+            fin: true,
         };
 
-        assert_eq!(wire_len, 1);
-        assert_eq!(&d[..wire_len], [0x07 as u8]);
+        assert_eq!(serde_json::to_string(&frame).unwrap(),
+                   concat!(r#"{"Stream":{"stream_id":4,"offset":0,"#,
+                           r#""data":"00ff6869","fin":true}}"#));
 
-        {
-            let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        fn parse(json: &str) -> serde_json::Result<FrameOwned> {
+            serde_json::from_str(json)
         }
+
+        assert_eq!(parse(r#"{"Datagram":{"data":"00FF"}}"#).unwrap(),
+                   Frame::Datagram { data: Cow::Owned(vec![0x00, 0xff]) });
+
+        assert!(parse(r#"{"Datagram":{"data":"0"}}"#).is_err());
+        assert!(parse(r#"{"Datagram":{"data":"zz"}}"#).is_err());
+        assert!(parse(r#"{"PathChallenge":{"data":"00"}}"#).is_err());
     }
 
     #[test]
-    fn new_connection_id() {
-        let mut d: [u8; 128] = [42; 128];
+    fn flow_control_len() {
+        let data = vec![42; 100];
 
-        let frame = Frame::NewConnectionId {
-            seq_num: 123213,
-            conn_id: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
-            reset_token: vec![0x42; 16],
+        let frame = Frame::Stream {
+            stream_id: 4,
+            offset: 1000,
+            data: Cow::Borrowed(&data),
+            fin: false,
+        };
+
+        assert_eq!(frame.flow_control_len(), 100);
+
+        // Type, stream ID, offset and length.
+        assert_eq!(frame.wire_len(), 100 + 1 + 1 + 2 + 2);
+
+        let frame = Frame::Datagram {
+            data: Cow::Borrowed(&data),
+        };
+
+        assert_eq!(frame.flow_control_len(), 100);
+
+        let frame = Frame::Crypto {
+            offset: 0,
+            data: Cow::Borrowed(&data),
         };
 
+        assert_eq!(frame.flow_control_len(), 0);
+
+        let frame = Frame::MaxData { max: 1000 };
+        assert_eq!(frame.flow_control_len(), 0);
+    }
+
+    // Parses a single frame and re-encodes it, checking that the encoding
+    // is identical to the input. This only makes sense for frame types that
+    // have one valid encoding (e.g. not STREAM, which can omit offset and
+    // length, or ACK).
+    fn assert_canonical(input: &[u8]) {
+        let mut buf = input.to_vec();
+        let mut out = vec![0; input.len()];
+
+        let mut b = octets::Bytes::new(&mut buf);
+        let frame = Frame::from_bytes(&mut b).unwrap();
+
         let wire_len = {
-            let mut b = octets::Bytes::new(&mut d);
+            let mut b = octets::Bytes::new(&mut out);
             frame.to_bytes(&mut b).unwrap()
         };
 
-        assert_eq!(wire_len, 37);
+        assert_eq!(wire_len, input.len());
+        assert_eq!(&out[..], input);
+    }
 
-        {
-            let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
-        }
+    #[test]
+    fn canonical() {
+        // PADDING
+        assert_canonical(&[0x00]);
+
+        // PING
+        assert_canonical(&[0x07]);
+
+        // NEW_CONNECTION_ID
+        let mut d = vec![0x0b, 0x41, 0x00, 0x04, 0xba, 0xdc, 0x0f, 0xfe];
+        d.extend_from_slice(&[0x42; 16]);
+        assert_canonical(&d);
     }
 
     #[test]
-    fn ack() {
+    fn stream_data() {
+        let frame = FrameOwned::stream(32, 12, b"hello".to_vec(), false);
+        assert_eq!(frame.stream_data(), Some(&b"hello"[..]));
+
+        let frame = Frame::Crypto {
+            offset: 12,
+            data: Cow::Borrowed(b"hello"),
+        };
+
+        assert_eq!(frame.stream_data(), None);
+    }
+
+    #[test]
+    fn ack_empty_ranges() {
         let mut d: [u8; 128] = [42; 128];
 
         let frame = Frame::ACK {
-            largest_ack: 2163721632,
-            ack_delay: 874656534
+            ack_delay: 0,
+            ranges: ranges::RangeSet::default(),
+            ecn: None,
         };
 
-        let wire_len = {
+        {
             let mut b = octets::Bytes::new(&mut d);
-            frame.to_bytes(&mut b).unwrap()
-        };
+            assert_eq!(frame.to_bytes(&mut b), Err(Error::InvalidFrame));
+            assert_eq!(b.off(), 0);
+        }
 
-        assert_eq!(wire_len, 15);
+        assert_eq!(&d[..], &[42; 128][..]);
+    }
+
+    #[test]
+    fn stream_len_width_invalid() {
+        let mut d: [u8; 128] = [42; 128];
+
+        let frame = FrameOwned::stream(4, 1000, vec![0xab; 100], false);
 
         {
             let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+
+            // Not a varint length.
+            assert_eq!(frame.to_bytes_with_stream_len_width(&mut b, 3),
+                       Err(Error::InvalidVarint));
+            assert_eq!(b.off(), 0);
+
+            // Too short for a length of 100.
+            assert_eq!(frame.to_bytes_with_stream_len_width(&mut b, 1),
+                       Err(Error::InvalidVarint));
+            assert_eq!(b.off(), 0);
         }
+
+        assert_eq!(&d[..], &[42; 128][..]);
     }
 
     #[test]
-    fn crypto() {
-        let mut d: [u8; 128] = [42; 128];
+    fn max_stream_data_direction() {
+        let frame = |stream_id| Frame::MaxStreamData { stream_id, max: 10 };
 
-        let mut data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        // Client and server bidirectional streams, and unidirectional
+        // streams opened locally, can be sent on.
+        for &stream_id in [0, 1, 2, 4, 6].iter() {
+            assert_eq!(validate_max_stream_data(&frame(stream_id), false),
+                       Ok(()));
+        }
 
-        let frame = Frame::Crypto {
-            offset: 1230976,
-            data: octets::Bytes::new(&mut data),
-        };
+        for &stream_id in [0, 1, 3, 5, 7].iter() {
+            assert_eq!(validate_max_stream_data(&frame(stream_id), true),
+                       Ok(()));
+        }
 
-        let wire_len = {
-            let mut b = octets::Bytes::new(&mut d);
-            frame.to_bytes(&mut b).unwrap()
-        };
+        // Unidirectional streams opened by the peer are receive-only.
+        for &stream_id in [3, 7].iter() {
+            assert_eq!(validate_max_stream_data(&frame(stream_id), false),
+                       Err(Error::InvalidStreamState));
+        }
 
-        assert_eq!(wire_len, 18);
+        for &stream_id in [2, 6].iter() {
+            assert_eq!(validate_max_stream_data(&frame(stream_id), true),
+                       Err(Error::InvalidStreamState));
+        }
+
+        // Other frames aren't checked.
+        let frame = Frame::StreamDataBlocked { stream_id: 3, limit: 10 };
+        assert_eq!(validate_max_stream_data(&frame, false), Ok(()));
+    }
+
+    #[test]
+    fn huge_declared_length() {
+        // length = 2^62 - 1, which doesn't fit in a 32-bit usize
+        let mut d = [0x31, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1];
 
         {
             let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+            assert_eq!(Frame::from_bytes(&mut b), Err(Error::BufferTooShort));
+        }
+
+        // stream_id = 0
+        let mut d = [0x12, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                     1];
+
+        let mut b = octets::Bytes::new(&mut d);
+        match Frame::from_bytes(&mut b) {
+            Err(Error::TruncatedFrame { available, .. }) =>
+                assert_eq!(available, 1),
+
+            r => panic!("unexpected result {:?}", r),
         }
     }
 
     #[test]
-    fn stream() {
-        let mut d: [u8; 128] = [42; 128];
+    fn ack_capped_ecn() {
+        let mut d: [u8; 256] = [42; 256];
 
-        let mut data: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let mut ranges = ranges::RangeSet::new();
 
-        let frame = Frame::Stream {
-            stream_id: 32,
-            offset: 1230976,
-            data: octets::Bytes::new(&mut data),
-            fin: true
-        };
+        for i in 0..100 {
+            ranges.insert_range(i * 10..i * 10 + 3);
+        }
 
-        let wire_len = {
+        let ecn = EcnCounts { ect0: 1, ect1: 2, ce: 300 };
+
+        // The counters take 4 bytes, which leaves room for 2 ranges fewer
+        // than without them.
+        let (wire_len, lowest) = {
             let mut b = octets::Bytes::new(&mut d);
-            frame.to_bytes(&mut b).unwrap()
+            write_ack_capped(&ranges, 0, Some(ecn), &mut b, 20).unwrap()
         };
 
-        assert_eq!(wire_len, 19);
+        assert_eq!(wire_len, 20);
+        assert_eq!(lowest, 940);
+        assert_eq!(d[0], 0x1a);
 
-        {
-            let mut b = octets::Bytes::new(&mut d);
-            assert_eq!(Frame::from_bytes(&mut b).unwrap(), frame);
+        let mut b = octets::Bytes::new(&mut d[..wire_len]);
+        match Frame::from_bytes(&mut b).unwrap() {
+            Frame::ACK { ranges: acked, ecn: Some(parsed), .. } => {
+                assert_eq!(acked.len(), 6);
+                assert_eq!(acked.smallest(), Some(lowest));
+                assert_eq!(acked.largest(), Some(992));
+                assert_eq!(parsed, ecn);
+            },
+
+            f => panic!("unexpected frame {:?}", f),
         }
+
+        assert_eq!(b.cap(), 0);
+    }
+
+    #[test]
+    fn schedule_padding_last() {
+        let padding = FrameOwned::padding(50);
+        let stream = FrameOwned::stream(4, 0, vec![0xab; 100], false);
+
+        let (selected, deferred) = schedule(vec![padding, stream], 60);
+
+        // The STREAM frame takes all 60 bytes, 4 of which are its header.
+        assert_eq!(selected, vec![FrameOwned::stream(4, 0, vec![0xab; 56],
+                                                     false)]);
+
+        assert_eq!(deferred, vec![
+            FrameOwned::stream(4, 56, vec![0xab; 44], false),
+            FrameOwned::padding(50),
+        ]);
+
+        // CRYPTO and DATAGRAM frames come after control frames, but before
+        // STREAM frames.
+        let crypto = Frame::Crypto {
+            offset: 0,
+            data: Cow::Owned(vec![1; 10]),
+        };
+
+        let stream = FrameOwned::stream(4, 0, vec![0xab; 10], false);
+
+        let (selected, deferred) =
+            schedule(vec![stream.clone(), crypto.clone(), Frame::Ping], 100);
+
+        assert_eq!(selected, vec![Frame::Ping, crypto, stream]);
+        assert!(deferred.is_empty());
+    }
+
+    #[test]
+    fn split_stream_zero_len() {
+        let frames = split_stream_into(0, 0, &[1; 3], true, &[0, 5]);
+        assert_eq!(frames, vec![FrameOwned::stream(0, 0, vec![1; 3], true)]);
+
+        let frames = split_stream_into(0, 0, &[1; 3], true, &[2, 0, 0, 5]);
+        assert_eq!(frames, vec![
+            FrameOwned::stream(0, 0, vec![1; 2], false),
+            FrameOwned::stream(0, 2, vec![1; 1], true),
+        ]);
+
+        // Nothing to send without a FIN.
+        assert!(split_stream_into(0, 0, &[], false, &[0, 5]).is_empty());
+
+        let frames = split_stream_into(0, 7, &[], true, &[0, 5]);
+        assert_eq!(frames, vec![FrameOwned::stream(0, 7, vec![], true)]);
     }
 }
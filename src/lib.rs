@@ -24,37 +24,67 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
 extern crate core;
+
+#[cfg(feature = "std")]
 extern crate libc;
+#[cfg(feature = "std")]
 extern crate ring;
 
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+#[cfg(feature = "std")]
 use std::cmp;
+#[cfg(feature = "std")]
 use std::mem;
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+#[cfg(feature = "std")]
 use std::collections::HashMap;
 
 pub const VERSION_DRAFT14: u32 = 0xff00000e;
 
-pub type Result<T> = ::std::result::Result<T, Error>;
+pub type Result<T> = ::core::result::Result<T, Error>;
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Error {
     WrongForm,
     UnknownVersion,
     UnknownPacket,
-    UnknownFrame,
+    UnknownFrame(u64),
     UnknownStream,
+    InvalidFrame,
+    TruncatedFrame { declared: usize, available: usize },
+    InvalidToken,
+    InvalidConnectionId,
+    InvalidVarint,
+    FinalSizeError,
+    CryptoBufferExceeded,
     BufferTooShort,
+    ValueOutOfRange,
     InvalidPacket,
     InvalidState,
+    InvalidStreamState,
     CryptoFail,
     TlsFail,
     Again,
     NothingToDo,
 }
 
+#[cfg(feature = "std")]
 #[derive(PartialEq, Copy, Clone, Debug)]
 enum State {
     Idle,
@@ -65,6 +95,7 @@ enum State {
     Draining,
 }
 
+#[cfg(feature = "std")]
 #[derive(Copy, Clone, Debug)]
 pub struct Config<'a> {
     pub version: u32,
@@ -78,6 +109,7 @@ pub struct Config<'a> {
     pub tls_certificate_key: &'a str,
 }
 
+#[cfg(feature = "std")]
 pub struct Conn {
     state: State,
 
@@ -101,10 +133,12 @@ pub struct Conn {
     is_server: bool,
 }
 
+#[cfg(feature = "std")]
 pub fn accept(config: Config) -> Result<Box<Conn>> {
     Conn::new(config, true)
 }
 
+#[cfg(feature = "std")]
 impl Conn {
     fn new_with_tls(config: Config, tls: tls::State, is_server: bool)
                                                     -> Result<Box<Conn>> {
@@ -230,7 +264,12 @@ impl Conn {
             let frame = frame::Frame::from_bytes(&mut payload)?;
 
             match frame {
-                frame::Frame::Padding => (),
+                frame::Frame::Padding { .. } => (),
+
+                // TODO: implement stream reset.
+                frame::Frame::ResetStream { .. } => {
+                    ack_only = false;
+                },
 
                 frame::Frame::ConnectionClose { .. } => {
                     ack_only = false;
@@ -244,38 +283,114 @@ impl Conn {
                     self.state = State::Draining;
                 },
 
+                // TODO: implement flow control.
+                frame::Frame::MaxData { .. } => {
+                    ack_only = false;
+                },
+
+                // TODO: implement flow control.
+                frame::Frame::MaxStreamData { .. } => {
+                    frame::validate_max_stream_data(&frame, self.is_server)?;
+
+                    ack_only = false;
+                },
+
+                // TODO: enforce stream limits.
+                frame::Frame::MaxStreamId { .. } => {
+                    ack_only = false;
+                },
+
                 frame::Frame::Ping => {
                     ack_only = false;
                 },
 
+                // TODO: implement flow control.
+                frame::Frame::DataBlocked { .. } => {
+                    ack_only = false;
+                },
+
+                // TODO: implement flow control.
+                frame::Frame::StreamDataBlocked { .. } => {
+                    ack_only = false;
+                },
+
+                // TODO: enforce stream limits.
+                frame::Frame::StreamIdBlocked { .. } => {
+                    ack_only = false;
+                },
+
                 frame::Frame::NewConnectionId { .. } => {
                     ack_only = false;
                 },
 
+                // TODO: implement stop sending.
+                frame::Frame::StopSending { .. } => {
+                    ack_only = false;
+                },
+
                 // TODO: implement ack and retransmission.
                 frame::Frame::ACK { .. } => (),
 
-                frame::Frame::Crypto { data, .. } => {
-                    match self.tls_state.provide_data(space.crypto_level,
-                                                      data.as_ref()) {
-                        Ok(_)  => (),
-                        Err(_) => return Err(Error::TlsFail),
+                // TODO: implement path challenge.
+                frame::Frame::PathChallenge { .. } => {
+                    ack_only = false;
+                },
+
+                // TODO: implement path response.
+                frame::Frame::PathResponse { .. } => {
+                    ack_only = false;
+                },
+
+                frame::Frame::Crypto { .. } => {
+                    space.crypto_stream.push_frame(&frame)?;
+
+                    // CRYPTO frames can arrive out of order, but TLS needs
+                    // the handshake data in order, so anything past a gap
+                    // stays buffered until the gap is filled.
+                    let data = space.crypto_stream.read_contiguous();
+
+                    if !data.is_empty() {
+                        match self.tls_state.provide_data(space.crypto_level,
+                                                          data) {
+                            Ok(_)  => (),
+                            Err(_) => return Err(Error::TlsFail),
+                        }
                     }
 
                     ack_only = false;
                 },
 
-                frame::Frame::Stream { stream_id, offset, data, .. } => {
+                frame::Frame::Stream { stream_id, .. } => {
                     let stream = self.streams.entry(stream_id).or_insert_with(|| {
                         // TODO: enforce stream limits
                         stream::Stream::new()
                     });
 
                     // TODO: enforce flow control
-                    stream.push_recv(data.as_ref(), offset as usize)?;
+                    stream.push_frame(&frame)?;
+
+                    ack_only = false;
+                },
 
+                // TODO: store token for future connections.
+                frame::Frame::NewToken { .. } => {
                     ack_only = false;
                 },
+
+                // TODO: confirm the handshake.
+                frame::Frame::HandshakeDone => {
+                    ack_only = false;
+                },
+
+                // TODO: deliver datagrams to the application.
+                frame::Frame::Datagram { .. } => {
+                    ack_only = false;
+                },
+
+                // Extension frames are only parsed for forwarding, and
+                // none of them is supported here.
+                frame::Frame::UnknownExt { frame_type, .. } =>
+                    return Err(Error::UnknownFrame(frame_type)),
             }
         }
 
@@ -353,9 +468,16 @@ impl Conn {
 
         // Create ACK frame.
         if space.need_ack.len() > 0 {
+            let mut ranges = ranges::RangeSet::default();
+
+            for pn in space.need_ack.drain(..) {
+                ranges.insert(pn)?;
+            }
+
             let frame = frame::Frame::ACK {
-                largest_ack: space.need_ack.pop().unwrap(),
                 ack_delay: 0,
+                ranges,
+                ecn: None,
             };
 
             frames.push(frame);
@@ -370,11 +492,11 @@ impl Conn {
 
             space.advance(crypto_len)?;
 
-            let crypto_buf = &mut space.crypto_buf[crypto_off..crypto_len];
+            let crypto_buf = &space.crypto_buf[crypto_off..crypto_len];
 
             let frame = frame::Frame::Crypto {
                 offset: crypto_off as u64,
-                data: octets::Bytes::new(crypto_buf),
+                data: Cow::Borrowed(crypto_buf),
             };
 
             frames.push(frame);
@@ -432,6 +554,15 @@ impl Conn {
         stream.pop_recv(buf)
     }
 
+    // Whether all the data on the stream was read, up to the FIN sent by
+    // the peer.
+    pub fn stream_finished(&self, stream_id: u64) -> Result<bool> {
+        match self.streams.get(&stream_id) {
+            Some(v) => Ok(v.is_fin()),
+            None => Err(Error::UnknownStream),
+        }
+    }
+
     pub fn stream_send(&mut self, stream_id: u64, buf: &mut [u8], fin: bool,
                        out: &mut [u8]) -> Result<usize> {
         let stream = match self.streams.get_mut(&stream_id) {
@@ -488,7 +619,7 @@ impl Conn {
         let overhead = space.overhead();
 
         let stream_len = cmp::min(buf.len(), left);
-        let stream_data = octets::Bytes::new(&mut buf[..stream_len]);
+        let stream_data = Cow::Borrowed(&buf[..stream_len]);
 
         // Create STREAM frame.
         let frame = frame::Frame::Stream {
@@ -562,6 +693,7 @@ impl Conn {
     }
 }
 
+#[cfg(feature = "std")]
 #[derive(Clone, Debug)]
 pub struct TransportParams {
     pub idle_timeout: u16,
@@ -579,6 +711,7 @@ pub struct TransportParams {
     // pub preferred_address: ...
 }
 
+#[cfg(feature = "std")]
 impl TransportParams {
     fn decode(buf: &mut [u8], _version: u32, is_server: bool)
                                                 -> Result<TransportParams> {
@@ -589,7 +722,8 @@ impl TransportParams {
 
         if !is_server {
             // Ignore supported versions from server.
-            b.get_bytes_with_u8_length()?;
+            let len = b.get_u8()?;
+            b.skip(len as usize)?;
         }
 
         let mut tp = TransportParams {
@@ -769,7 +903,7 @@ impl TransportParams {
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
 
@@ -817,11 +951,17 @@ mod tests {
     }
 }
 
+pub mod frame;
+pub mod octets;
+#[cfg(feature = "std")]
 pub mod packet;
+#[cfg(feature = "std")]
 pub mod rand;
+pub mod ranges;
 
+#[cfg(feature = "std")]
 mod crypto;
-mod frame;
+#[cfg(feature = "std")]
 mod stream;
+#[cfg(feature = "std")]
 mod tls;
-mod octets;
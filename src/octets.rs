@@ -24,8 +24,10 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::mem;
-use std::ptr;
+use core::mem;
+use core::ptr;
+
+use alloc::vec::Vec;
 
 use ::Result;
 use ::Error;
@@ -40,11 +42,11 @@ macro_rules! peek_u {
             return Err(Error::BufferTooShort);
         }
 
-        let out = unsafe {
-            ptr::read_unaligned(src.as_ptr() as *const $ty)
-        };
+        // Copy into an array first, to avoid unaligned access to the buffer.
+        let mut out = [0; mem::size_of::<$ty>()];
+        out.copy_from_slice(&src[..len]);
 
-        Ok(<$ty>::from_be(out))
+        Ok(<$ty>::from_be_bytes(out))
     });
 }
 
@@ -69,9 +71,7 @@ macro_rules! put_u {
             return Err(Error::BufferTooShort)
         }
 
-        unsafe {
-            ptr::write_unaligned(dst.as_mut_ptr() as *mut $ty, <$ty>::to_be($v));
-        }
+        dst[..len].copy_from_slice(&<$ty>::to_be_bytes($v));
 
         $b.off += len;
 
@@ -107,7 +107,7 @@ impl<'a> Bytes<'a> {
         get_u!(self, u8)
     }
 
-    pub fn peek_u8(&mut self) -> Result<u8> {
+    pub fn peek_u8(&self) -> Result<u8> {
         peek_u!(self, u8)
     }
 
@@ -119,7 +119,7 @@ impl<'a> Bytes<'a> {
         get_u!(self, u16)
     }
 
-    pub fn peek_u16(&mut self) -> Result<u16> {
+    pub fn peek_u16(&self) -> Result<u16> {
         peek_u!(self, u16)
     }
 
@@ -127,11 +127,47 @@ impl<'a> Bytes<'a> {
         put_u!(self, u16, v)
     }
 
+    pub fn get_u24(&mut self) -> Result<u32> {
+        let out = self.peek_u24()?;
+
+        self.off += 3;
+
+        Ok(out)
+    }
+
+    pub fn peek_u24(&self) -> Result<u32> {
+        let src = &self.buf[self.off..];
+
+        if src.len() < 3 {
+            return Err(Error::BufferTooShort);
+        }
+
+        Ok(u32::from(src[0]) << 16 | u32::from(src[1]) << 8 | u32::from(src[2]))
+    }
+
+    pub fn put_u24(&mut self, v: u32) -> Result<&mut [u8]> {
+        if v > 0x00ff_ffff {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        if self.cap() < 3 {
+            return Err(Error::BufferTooShort);
+        }
+
+        let dst = &mut self.buf[self.off..];
+
+        dst[..3].copy_from_slice(&v.to_be_bytes()[1..]);
+
+        self.off += 3;
+
+        Ok(dst)
+    }
+
     pub fn get_u32(&mut self) -> Result<u32> {
         get_u!(self, u32)
     }
 
-    pub fn peek_u32(&mut self) -> Result<u32> {
+    pub fn peek_u32(&self) -> Result<u32> {
         peek_u!(self, u32)
     }
 
@@ -143,57 +179,92 @@ impl<'a> Bytes<'a> {
         get_u!(self, u64)
     }
 
+    pub fn peek_u64(&self) -> Result<u64> {
+        peek_u!(self, u64)
+    }
+
     pub fn put_u64(&mut self, v: u64) -> Result<&mut [u8]> {
         put_u!(self, u64, v)
     }
 
     pub fn get_varint(&mut self) -> Result<u64> {
-        let first = self.peek_u8()?;
-
-        let len = match first >> 6 {
-            0 => 1,
-            1 => 2,
-            2 => 4,
-            3 => 8,
-            _ => return Err(Error::BufferTooShort),
-        };
+        let len = varint_parse_len(self.peek_u8()?);
 
-        let mut vec = self.get_bytes(len)?.to_vec();
+        let out = self.peek_varint()?;
 
-        // Mask the 2 most significant bits to remove the encoded length.
-        vec[0] &= 0x3f;
+        self.off += len;
+
+        Ok(out)
+    }
+
+    // Like `get_varint()`, but only accepts the shortest possible encoding
+    // of the value.
+    pub fn get_varint_strict(&mut self) -> Result<u64> {
+        let len = varint_parse_len(self.peek_u8()?);
 
-        let mut b = Bytes::new(&mut vec);
+        let out = self.peek_varint()?;
+
+        if varint_len(out) != len {
+            return Err(Error::InvalidVarint);
+        }
+
+        self.off += len;
 
+        Ok(out)
+    }
+
+    pub fn peek_varint(&self) -> Result<u64> {
+        let len = varint_parse_len(self.peek_u8()?);
+
+        // Mask the 2 most significant bits to remove the encoded length.
         let out = match len {
-            1 => u64::from(b.get_u8()?),
-            2 => u64::from(b.get_u16()?),
-            4 => u64::from(b.get_u32()?),
-            8 => b.get_u64()?,
-            _ => return Err(Error::BufferTooShort),
+            1 => u64::from(self.peek_u8()? & 0x3f),
+            2 => u64::from(self.peek_u16()? & 0x3fff),
+            4 => u64::from(self.peek_u32()? & 0x3fff_ffff),
+            8 => self.peek_u64()? & 0x3fff_ffff_ffff_ffff,
+            _ => unreachable!(),
         };
 
         Ok(out)
     }
 
     pub fn put_varint(&mut self, v: u64) -> Result<()> {
-        if self.cap() == 0 {
+        self.put_varint_with_len(v, varint_len(v))
+    }
+
+    // Encodes the value using exactly `len` bytes, even when a shorter
+    // encoding would do.
+    pub fn put_varint_with_len(&mut self, v: u64, len: usize) -> Result<()> {
+        if self.cap() < len {
             return Err(Error::BufferTooShort);
         }
 
-        if v < 63 {
-            self.put_u8(v as u8)?;
-        } else if v < 16383 {
-            let buf = self.put_u16(v as u16)?;
-            buf[0] |= 0x40;
-        } else if v < 1_073_741_823 {
-            let buf = self.put_u32(v as u32)?;
-            buf[0] |= 0x80;
-        } else if v < 4_611_686_018_427_387_903 {
-            let buf = self.put_u64(v)?;
-            buf[0] |= 0xc0;
-        } else {
-            return Err(Error::BufferTooShort);
+        // The value is too large for a varint, or for the requested length.
+        if varint_len(v) == 0 || varint_len(v) > len {
+            return Err(Error::InvalidVarint);
+        }
+
+        match len {
+            1 => {
+                self.put_u8(v as u8)?;
+            },
+
+            2 => {
+                let buf = self.put_u16(v as u16)?;
+                buf[0] |= 0x40;
+            },
+
+            4 => {
+                let buf = self.put_u32(v as u32)?;
+                buf[0] |= 0x80;
+            },
+
+            8 => {
+                let buf = self.put_u64(v)?;
+                buf[0] |= 0xc0;
+            },
+
+            _ => return Err(Error::InvalidVarint),
         };
 
         Ok(())
@@ -219,9 +290,28 @@ impl<'a> Bytes<'a> {
         self.get_bytes(len as usize)
     }
 
+    // The length-prefixed getters leave the buffer untouched when the
+    // declared length exceeds the remaining capacity.
     pub fn get_bytes_with_u16_length(&mut self) -> Result<Bytes> {
-        let len = self.get_u16()?;
-        self.get_bytes(len as usize)
+        let len = self.peek_u16()? as usize;
+
+        if self.cap() - 2 < len {
+            return Err(Error::BufferTooShort);
+        }
+
+        self.skip(2)?;
+        self.get_bytes(len)
+    }
+
+    pub fn get_bytes_with_u32_length(&mut self) -> Result<Bytes> {
+        let len = self.peek_u32()? as usize;
+
+        if self.cap() - 4 < len {
+            return Err(Error::BufferTooShort);
+        }
+
+        self.skip(4)?;
+        self.get_bytes(len)
     }
 
     pub fn get_bytes_with_varint_length(&mut self) -> Result<Bytes> {
@@ -264,6 +354,46 @@ impl<'a> Bytes<'a> {
         Ok(())
     }
 
+    pub fn put_bytes_with_u16_length(&mut self, v: &[u8]) -> Result<()> {
+        if v.len() > usize::from(u16::MAX) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        if self.cap() < 2 + v.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        self.put_u16(v.len() as u16)?;
+        self.put_bytes(v)
+    }
+
+    pub fn put_bytes_with_u32_length(&mut self, v: &[u8]) -> Result<()> {
+        if v.len() as u64 > u64::from(u32::MAX) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        if self.cap() < 4 + v.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        self.put_u32(v.len() as u32)?;
+        self.put_bytes(v)
+    }
+
+    // Returns the number of bytes written, including the length.
+    pub fn put_bytes_with_varint_length(&mut self, v: &[u8]) -> Result<usize> {
+        let len = varint_len(v.len() as u64) + v.len();
+
+        if self.cap() < len {
+            return Err(Error::BufferTooShort);
+        }
+
+        self.put_varint(v.len() as u64)?;
+        self.put_bytes(v)?;
+
+        Ok(len)
+    }
+
     pub fn split_at(&mut self, off: usize) -> Result<(Bytes, Bytes)> {
         if self.cap() + self.off < off {
             return Err(Error::BufferTooShort);
@@ -313,9 +443,27 @@ impl<'a> Bytes<'a> {
         self.off
     }
 
+    // Moves the cursor to `off`, e.g. to go back to a position saved with
+    // `off()`. The offset is relative to the start of this buffer, so it
+    // can't be used to reach outside of it.
+    pub fn set_off(&mut self, off: usize) -> Result<()> {
+        if off > self.buf.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        self.off = off;
+
+        Ok(())
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         self.as_ref().to_vec()
     }
+
+    pub fn into_slice(self) -> &'a [u8] {
+        let buf: &'a [u8] = self.buf;
+        &buf[self.off..]
+    }
 }
 
 impl<'a> AsRef<[u8]> for Bytes<'a> {
@@ -330,20 +478,41 @@ impl<'a> AsMut<[u8]> for Bytes<'a> {
     }
 }
 
+// Returns the length of a varint from its first byte.
+pub fn varint_parse_len(first: u8) -> usize {
+    match first >> 6 {
+        0 => 1,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    }
+}
+
+// Returns the largest value that can be encoded as a varint.
+pub const fn max_varint() -> u64 {
+    (1 << 62) - 1
+}
+
 pub fn varint_len(v: u64) -> usize {
-    if v < 63 {
+    if v <= 63 {
         1
-    } else if v < 16383 {
+    } else if v <= 16383 {
         2
-    } else if v < 1_073_741_823 {
+    } else if v <= 1_073_741_823 {
         4
-    } else if v < 4_611_686_018_427_387_903 {
+    } else if v <= max_varint() {
         8
     } else {
         0
     }
 }
 
+// Returns the minimal encoding width of a varint, i.e. 1, 2, 4 or 8, or 0
+// if the value is too large to be encoded.
+pub fn varint_width(v: u64) -> u8 {
+    varint_len(v) as u8
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -368,6 +537,10 @@ mod tests {
         assert!(b.skip(10).is_ok());
         assert_eq!(b.cap(), 0);
         assert_eq!(b.off(), 15);
+
+        assert!(b.skip(0).is_ok());
+        assert!(b.skip(1).is_err());
+        assert_eq!(b.off(), 15);
     }
 
     #[test]
@@ -400,6 +573,56 @@ mod tests {
         assert!(b.get_u64().is_err());
     }
 
+    #[test]
+    fn u24() {
+        let mut d: [u8; 7] = [0; 7];
+
+        {
+            let mut b = Bytes::new(&mut d);
+
+            assert!(b.put_u24(0x010203).is_ok());
+            assert!(b.put_u24(0xff_ffff).is_ok());
+            assert_eq!(b.off(), 6);
+
+            assert!(b.put_u24(0).is_err());
+            assert_eq!(b.off(), 6);
+        }
+
+        assert_eq!(&d, &[1, 2, 3, 0xff, 0xff, 0xff, 0]);
+
+        {
+            let mut b = Bytes::new(&mut d);
+
+            assert_eq!(b.peek_u24().unwrap(), 0x010203);
+            assert_eq!(b.off(), 0);
+
+            assert_eq!(b.get_u24().unwrap(), 0x010203);
+            assert_eq!(b.get_u24().unwrap(), 0xff_ffff);
+            assert_eq!(b.off(), 6);
+
+            assert!(b.get_u24().is_err());
+            assert_eq!(b.off(), 6);
+        }
+
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.put_u24(0x100_0000), Err(Error::ValueOutOfRange));
+        assert_eq!(b.off(), 0);
+    }
+
+    #[test]
+    fn get_u_unaligned() {
+        let mut d: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
+                               15];
+
+        // Start at an odd offset, so that no value is aligned to its size.
+        let mut b = Bytes::new(&mut d[1..]);
+
+        assert_eq!(b.get_u16().unwrap(), 0x102);
+        assert_eq!(b.get_u32().unwrap(), 0x3040506);
+        assert_eq!(b.get_u64().unwrap(), 0x708090a0b0c0d0e);
+        assert_eq!(b.cap(), 1);
+    }
+
     #[test]
     fn peek_u() {
         let mut d: [u8; 2] = [1, 2];
@@ -447,6 +670,148 @@ mod tests {
         assert!(b.get_bytes(2).is_err());
     }
 
+    #[test]
+    fn get_bytes_sub_buffer() {
+        let mut d: [u8; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let mut b = Bytes::new(&mut d);
+
+        {
+            let mut sub = b.get_bytes(4).unwrap();
+            assert_eq!(sub.cap(), 4);
+            assert_eq!(sub.off(), 0);
+
+            // The sub-buffer can't be read past its end.
+            assert_eq!(sub.get_u16().unwrap(), 0x102);
+            assert!(sub.get_u32().is_err());
+            assert_eq!(sub.get_u16().unwrap(), 0x304);
+            assert!(sub.get_u8().is_err());
+        }
+
+        assert_eq!(b.cap(), 6);
+        assert_eq!(b.off(), 4);
+        assert_eq!(b.get_u8().unwrap(), 5);
+
+        assert!(b.get_bytes(6).is_err());
+        assert_eq!(b.off(), 5);
+    }
+
+    #[test]
+    fn get_bytes_empty() {
+        let mut d: [u8; 1] = [0x00];
+        let mut b = Bytes::new(&mut d);
+
+        assert_eq!(b.get_bytes_with_varint_length().unwrap().as_ref(), []);
+        assert_eq!(b.cap(), 0);
+        assert_eq!(b.off(), 1);
+
+        assert_eq!(b.get_bytes(0).unwrap().as_ref(), []);
+        assert_eq!(b.cap(), 0);
+        assert_eq!(b.off(), 1);
+    }
+
+    #[test]
+    fn get_bytes_with_length() {
+        let mut d: [u8; 11] = [
+            0x00, 0x03, 1, 2, 3, 0x00, 0x00, 0x00, 0x02, 4, 5
+        ];
+        let mut b = Bytes::new(&mut d);
+
+        assert_eq!(b.get_bytes_with_u16_length().unwrap().as_ref(), [1, 2, 3]);
+        assert_eq!(b.off(), 5);
+
+        assert_eq!(b.get_bytes_with_u32_length().unwrap().as_ref(), [4, 5]);
+        assert_eq!(b.off(), 11);
+        assert_eq!(b.cap(), 0);
+    }
+
+    #[test]
+    fn get_bytes_with_length_truncated() {
+        let mut d: [u8; 5] = [0x00, 0x05, 1, 2, 3];
+        let mut b = Bytes::new(&mut d);
+
+        assert!(b.get_bytes_with_u16_length().is_err());
+        assert_eq!(b.off(), 0);
+
+        assert!(b.get_bytes_with_u32_length().is_err());
+        assert_eq!(b.off(), 0);
+
+        let mut d: [u8; 3] = [0x00, 0x00, 0x00];
+        let mut b = Bytes::new(&mut d);
+
+        // Not enough bytes left for the length itself.
+        assert!(b.get_bytes_with_u32_length().is_err());
+        assert_eq!(b.off(), 0);
+    }
+
+    #[test]
+    fn put_bytes_with_length() {
+        let mut d: [u8; 11] = [0; 11];
+
+        {
+            let mut b = Bytes::new(&mut d);
+
+            assert!(b.put_bytes_with_u16_length(&[1, 2, 3]).is_ok());
+            assert_eq!(b.off(), 5);
+
+            assert!(b.put_bytes_with_u32_length(&[4, 5]).is_ok());
+            assert_eq!(b.off(), 11);
+        }
+
+        let exp: [u8; 11] = [0x00, 0x03, 1, 2, 3, 0x00, 0x00, 0x00, 0x02, 4, 5];
+        assert_eq!(&d, &exp);
+
+        let mut d: [u8; 5] = [0; 5];
+        let mut b = Bytes::new(&mut d);
+
+        // Too short for the length and the data, so nothing is written.
+        assert_eq!(b.put_bytes_with_u32_length(&[1, 2]),
+                   Err(Error::BufferTooShort));
+        assert_eq!(b.off(), 0);
+
+        assert_eq!(b.put_bytes_with_u16_length(&[1, 2, 3, 4]),
+                   Err(Error::BufferTooShort));
+        assert_eq!(b.off(), 0);
+
+        let big = vec![0; 65536];
+        let mut d = vec![0; 65540];
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.put_bytes_with_u16_length(&big),
+                   Err(Error::ValueOutOfRange));
+        assert_eq!(b.off(), 0);
+        assert!(b.put_bytes_with_u32_length(&big).is_ok());
+    }
+
+    #[test]
+    fn put_bytes_with_varint_length() {
+        let mut d: [u8; 71] = [0; 71];
+
+        {
+            let mut b = Bytes::new(&mut d);
+
+            assert_eq!(b.put_bytes_with_varint_length(&[1, 2, 3]).unwrap(), 4);
+            assert_eq!(b.put_bytes_with_varint_length(&[]).unwrap(), 1);
+            assert_eq!(b.put_bytes_with_varint_length(&[4; 64]).unwrap(), 66);
+            assert_eq!(b.off(), 71);
+
+            assert!(b.put_bytes_with_varint_length(&[]).is_err());
+        }
+
+        {
+            let mut b = Bytes::new(&mut d);
+
+            assert_eq!(b.get_bytes_with_varint_length().unwrap().as_ref(),
+                       [1, 2, 3]);
+            assert_eq!(b.get_bytes_with_varint_length().unwrap().as_ref(), []);
+            assert_eq!(b.get_bytes_with_varint_length().unwrap().as_ref(),
+                       &[4; 64][..]);
+        }
+
+        // Too short for the data, so nothing is written.
+        let mut b = Bytes::new(&mut d[..3]);
+        assert!(b.put_bytes_with_varint_length(&[1, 2, 3]).is_err());
+        assert_eq!(b.off(), 0);
+    }
+
     #[test]
     fn peek_bytes() {
         let mut d: [u8; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
@@ -498,6 +863,97 @@ mod tests {
         assert_eq!(b.off(), 1);
     }
 
+    #[test]
+    fn get_varint_strict() {
+        let mut d: [u8; 2] = [0x40, 0x05];
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.get_varint_strict(), Err(Error::InvalidVarint));
+        assert_eq!(b.off(), 0);
+        assert_eq!(b.get_varint().unwrap(), 5);
+        assert_eq!(b.off(), 2);
+
+        let mut d: [u8; 8] = [0xc0, 0, 0, 0, 0, 0, 0, 0x01];
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.get_varint_strict(), Err(Error::InvalidVarint));
+
+        let mut d: [u8; 8] = [0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c];
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.get_varint_strict().unwrap(), 151288809941952652);
+
+        let mut d: [u8; 2] = [0x7b, 0xbd];
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.get_varint_strict().unwrap(), 15293);
+
+        let mut d: [u8; 1] = [0x25];
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.get_varint_strict().unwrap(), 37);
+        assert_eq!(b.off(), 1);
+
+        let mut d: [u8; 1] = [0x40];
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.get_varint_strict(), Err(Error::BufferTooShort));
+    }
+
+    #[test]
+    fn set_off() {
+        let mut d: [u8; 6] = [0x25, 0x9d, 0x7f, 0x3e, 0x7d, 0x01];
+        let mut b = Bytes::new(&mut d);
+
+        assert_eq!(b.get_varint().unwrap(), 37);
+
+        let off = b.off();
+        assert_eq!(b.get_varint().unwrap(), 494878333);
+        assert_eq!(b.off(), 5);
+
+        assert!(b.set_off(off).is_ok());
+        assert_eq!(b.cap(), 5);
+        assert_eq!(b.get_varint().unwrap(), 494878333);
+
+        assert!(b.set_off(0).is_ok());
+        assert_eq!(b.get_varint().unwrap(), 37);
+
+        assert!(b.set_off(6).is_ok());
+        assert_eq!(b.cap(), 0);
+
+        assert!(b.set_off(7).is_err());
+        assert_eq!(b.off(), 6);
+    }
+
+    #[test]
+    fn peek_varint() {
+        let mut d: [u8; 9] = [
+            0x25, 0x9d, 0x7f, 0x3e, 0x7d, 0x40, 0x25, 0x7b, 0xbd
+        ];
+        let mut b = Bytes::new(&mut d);
+
+        assert_eq!(b.peek_varint().unwrap(), 37);
+        assert_eq!(b.peek_varint().unwrap(), 37);
+        assert_eq!(b.off(), 0);
+        assert_eq!(b.get_varint().unwrap(), 37);
+        assert_eq!(b.off(), 1);
+
+        assert_eq!(b.peek_varint().unwrap(), 494878333);
+        assert_eq!(b.off(), 1);
+        assert_eq!(b.get_varint().unwrap(), 494878333);
+        assert_eq!(b.off(), 5);
+
+        assert_eq!(b.peek_varint().unwrap(), 37);
+        assert_eq!(b.off(), 5);
+        assert_eq!(b.get_varint().unwrap(), 37);
+        assert_eq!(b.off(), 7);
+
+        assert_eq!(b.peek_u8().unwrap(), 0x7b);
+        assert_eq!(b.off(), 7);
+
+        // Truncated varint.
+        b.skip(1).unwrap();
+        assert!(b.peek_varint().is_err());
+        assert_eq!(b.off(), 8);
+
+        assert!(b.get_varint().is_err());
+        assert_eq!(b.off(), 8);
+    }
+
     #[test]
     fn put_varint() {
         let mut d: [u8; 8] = [0; 8];
@@ -551,6 +1007,58 @@ mod tests {
         assert_eq!(&d, &exp);
     }
 
+    #[test]
+    fn varint_boundaries() {
+        let values: [(u64, usize); 8] = [
+            (63, 1), (64, 2),
+            (16383, 2), (16384, 4),
+            (1_073_741_823, 4), (1_073_741_824, 8),
+            (4_611_686_018_427_387_903, 8), (4_611_686_018_427_387_904, 0),
+        ];
+
+        for &(v, len) in values.iter() {
+            assert_eq!(varint_len(v), len);
+            assert_eq!(varint_width(v) as usize, len);
+
+            let mut d: [u8; 8] = [0; 8];
+
+            let mut b = Bytes::new(&mut d);
+
+            if len == 0 {
+                assert!(b.put_varint(v).is_err());
+                continue;
+            }
+
+            assert!(b.put_varint(v).is_ok());
+            assert_eq!(b.off(), len);
+
+            let mut b = Bytes::new(&mut d[..len]);
+            assert_eq!(b.get_varint().unwrap(), v);
+
+            // Any wider encoding works too, but narrower ones don't.
+            for &width in [1, 2, 4, 8].iter() {
+                let mut d: [u8; 8] = [0; 8];
+
+                let mut b = Bytes::new(&mut d);
+
+                if width < len {
+                    assert!(b.put_varint_with_len(v, width).is_err());
+                    continue;
+                }
+
+                assert!(b.put_varint_with_len(v, width).is_ok());
+                assert_eq!(b.off(), width);
+
+                let mut b = Bytes::new(&mut d[..width]);
+                assert_eq!(b.get_varint().unwrap(), v);
+            }
+        }
+
+        assert_eq!(max_varint(), 4_611_686_018_427_387_903);
+        assert_eq!(varint_width(max_varint()), 8);
+        assert_eq!(varint_width(max_varint() + 1), 0);
+    }
+
     #[test]
     fn put_u() {
         let mut d: [u8; 15] = [0; 15];
@@ -629,4 +1137,29 @@ mod tests {
         assert_eq!(last.off(), 0);
         assert_eq!(last.as_ref(), b"world");
     }
+
+    #[test]
+    fn put_varint_with_len() {
+        let mut d: [u8; 8] = [0; 8];
+
+        {
+            let mut b = Bytes::new(&mut d);
+            assert!(b.put_varint_with_len(37, 4).is_ok());
+            assert_eq!(b.off(), 4);
+        }
+
+        assert_eq!(&d[..4], &[0x80, 0x00, 0x00, 0x25]);
+
+        {
+            let mut b = Bytes::new(&mut d);
+            assert_eq!(b.get_varint().unwrap(), 37);
+            assert_eq!(b.off(), 4);
+        }
+
+        let mut b = Bytes::new(&mut d);
+        assert_eq!(b.put_varint_with_len(16384, 2), Err(Error::InvalidVarint));
+        assert_eq!(b.put_varint_with_len(37, 3), Err(Error::InvalidVarint));
+        assert_eq!(b.put_varint(1 << 62), Err(Error::InvalidVarint));
+        assert_eq!(b.off(), 0);
+    }
 }
@@ -32,6 +32,7 @@ use ::Error;
 use octets;
 use crypto;
 use rand;
+use stream;
 
 const FORM_BIT: u8 = 0x80;
 const KEY_PHASE_BIT: u8 = 0x40;
@@ -381,6 +382,8 @@ pub struct PktNumSpace {
 
     pub crypto_offset: usize,
     pub crypto_buf: Vec<u8>,
+
+    pub crypto_stream: stream::CryptoBuffer,
 }
 
 impl PktNumSpace {
@@ -401,6 +404,8 @@ impl PktNumSpace {
 
             crypto_offset: 0,
             crypto_buf: Vec::new(),
+
+            crypto_stream: stream::CryptoBuffer::new(),
         }
     }
 
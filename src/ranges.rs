@@ -0,0 +1,375 @@
+// Copyright (c) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright
+//       notice, this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use ::Result;
+use ::Error;
+
+use core::cmp;
+use core::ops::Range;
+use core::slice;
+
+use alloc::vec::Vec;
+
+// A set of u64 values (e.g. packet numbers) stored as sorted ranges. Ranges
+// that overlap or touch are always merged, so the set is kept in its most
+// compact form.
+//
+// When serialized, the set is just a list of ranges, which are merged again
+// on the way back in.
+#[derive(Clone, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde",
+           serde(from = "Vec<Range<u64>>", into = "Vec<Range<u64>>"))]
+pub struct RangeSet {
+    inner: Vec<Range<u64>>,
+}
+
+impl RangeSet {
+    pub fn new() -> RangeSet {
+        Self::default()
+    }
+
+    // Ranges are half-open, so u64::MAX can't be inserted.
+    pub fn insert(&mut self, item: u64) -> Result<()> {
+        let end = item.checked_add(1).ok_or(Error::ValueOutOfRange)?;
+
+        self.insert_range(item..end);
+
+        Ok(())
+    }
+
+    pub fn insert_range(&mut self, item: Range<u64>) {
+        if item.start >= item.end {
+            return;
+        }
+
+        let mut start = item.start;
+        let mut end = item.end;
+
+        // Find the first range that overlaps or touches the new one, and
+        // merge it and all the following ones that do as well.
+        let first = self.inner.partition_point(|r| r.end < start);
+
+        let mut last = first;
+
+        while last < self.inner.len() && self.inner[last].start <= end {
+            start = cmp::min(start, self.inner[last].start);
+            end = cmp::max(end, self.inner[last].end);
+
+            last += 1;
+        }
+
+        self.inner.splice(first..last, Some(start..end));
+    }
+
+    pub fn contains(&self, item: u64) -> bool {
+        self.inner.binary_search_by(|r| {
+            if r.end <= item {
+                cmp::Ordering::Less
+            } else if r.start > item {
+                cmp::Ordering::Greater
+            } else {
+                cmp::Ordering::Equal
+            }
+        }).is_ok()
+    }
+
+    // Returns the smallest value in the set.
+    pub fn smallest(&self) -> Option<u64> {
+        self.inner.first().map(|r| r.start)
+    }
+
+    // Returns the largest value in the set.
+    pub fn largest(&self) -> Option<u64> {
+        self.inner.last().map(|r| r.end - 1)
+    }
+
+    // Returns the number of disjoint ranges in the set.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    // Returns the values that are in this set but not in `other`, e.g. the
+    // packets acknowledged by an ACK that weren't already known to be.
+    //
+    // Both sets are walked once in order, and the result comes out sorted and
+    // without touching ranges, so it doesn't need to be merged.
+    pub fn difference(&self, other: &RangeSet) -> RangeSet {
+        let mut inner = Vec::new();
+
+        let mut others = other.inner.iter().peekable();
+
+        for r in self.iter() {
+            let mut start = r.start;
+
+            while let Some(o) = others.peek() {
+                if o.start >= r.end {
+                    break;
+                }
+
+                if o.start > start {
+                    inner.push(start..o.start);
+                }
+
+                start = cmp::max(start, o.end);
+
+                // The range might also overlap the next one in this set.
+                if o.end > r.end {
+                    break;
+                }
+
+                others.next();
+            }
+
+            if start < r.end {
+                inner.push(start..r.end);
+            }
+        }
+
+        RangeSet { inner }
+    }
+
+    // Iterates over the ranges in the set in ascending order.
+    pub fn iter(&self) -> slice::Iter<Range<u64>> {
+        self.inner.iter()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<Vec<Range<u64>>> for RangeSet {
+    fn from(v: Vec<Range<u64>>) -> RangeSet {
+        let mut r = RangeSet::new();
+
+        for item in v {
+            r.insert_range(item);
+        }
+
+        r
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<RangeSet> for Vec<Range<u64>> {
+    fn from(r: RangeSet) -> Vec<Range<u64>> {
+        r.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(r: &RangeSet) -> Vec<Range<u64>> {
+        r.iter().cloned().collect()
+    }
+
+    #[test]
+    fn insert_non_overlapping() {
+        let mut r = RangeSet::new();
+        assert_eq!(r.len(), 0);
+
+        r.insert_range(4..7);
+        r.insert_range(9..12);
+        r.insert_range(0..2);
+
+        assert_eq!(ranges(&r), vec![0..2, 4..7, 9..12]);
+    }
+
+    #[test]
+    fn insert_contiguous() {
+        let mut r = RangeSet::new();
+
+        r.insert_range(1..4);
+        r.insert_range(4..7);
+        assert_eq!(ranges(&r), vec![1..7]);
+
+        r.insert_range(0..1);
+        assert_eq!(ranges(&r), vec![0..7]);
+
+        r.insert(7).unwrap();
+        assert_eq!(ranges(&r), vec![0..8]);
+    }
+
+    #[test]
+    fn insert_overlapping() {
+        let mut r = RangeSet::new();
+
+        r.insert_range(10..20);
+        r.insert_range(15..25);
+        assert_eq!(ranges(&r), vec![10..25]);
+
+        r.insert_range(5..12);
+        assert_eq!(ranges(&r), vec![5..25]);
+
+        r.insert_range(12..14);
+        assert_eq!(ranges(&r), vec![5..25]);
+    }
+
+    #[test]
+    fn gap_closed() {
+        let mut r = RangeSet::new();
+
+        for pn in [0, 1, 2, 5, 6, 9].iter() {
+            r.insert(*pn).unwrap();
+        }
+
+        assert_eq!(ranges(&r), vec![0..3, 5..7, 9..10]);
+
+        // An ACK frame encodes the first range separately, so the block
+        // count is the number of ranges minus one.
+        assert_eq!(r.len() - 1, 2);
+
+        // Filling the gaps in reverse order merges everything back into a
+        // single range.
+        r.insert_range(7..9);
+        assert_eq!(ranges(&r), vec![0..3, 5..10]);
+
+        r.insert_range(3..5);
+        assert_eq!(ranges(&r), vec![0..10]);
+        assert_eq!(r.len() - 1, 0);
+    }
+
+    #[test]
+    fn insert_spanning() {
+        let mut r = RangeSet::new();
+
+        r.insert_range(1..2);
+        r.insert_range(4..5);
+        r.insert_range(7..8);
+
+        r.insert_range(0..10);
+        assert_eq!(ranges(&r), vec![0..10]);
+    }
+
+    #[test]
+    fn insert_empty() {
+        let mut r = RangeSet::new();
+
+        r.insert_range(5..5);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn difference() {
+        let mut prev = RangeSet::new();
+        prev.insert_range(0..5);
+        prev.insert_range(8..10);
+
+        // Packet 5 was declared lost and is now acknowledged along with
+        // some newer packets.
+        let mut ack = RangeSet::new();
+        ack.insert_range(3..6);
+        ack.insert_range(9..14);
+
+        assert_eq!(ranges(&ack.difference(&prev)), vec![5..6, 10..14]);
+        assert_eq!(ranges(&prev.difference(&ack)), vec![0..3, 8..9]);
+
+        assert!(prev.difference(&prev).is_empty());
+        assert_eq!(prev.difference(&RangeSet::new()), prev);
+        assert!(RangeSet::new().difference(&prev).is_empty());
+    }
+
+    #[test]
+    fn contains() {
+        let mut r = RangeSet::new();
+        assert!(!r.contains(0));
+
+        r.insert_range(4..7);
+        r.insert_range(9..12);
+
+        assert!(!r.contains(3));
+        assert!(r.contains(4));
+        assert!(r.contains(6));
+        assert!(!r.contains(7));
+        assert!(!r.contains(8));
+        assert!(r.contains(9));
+        assert!(r.contains(11));
+        assert!(!r.contains(12));
+    }
+
+    #[test]
+    fn contains_large() {
+        let mut r = RangeSet::new();
+
+        for i in 0..10000 {
+            r.insert_range(i * 10..i * 10 + 5);
+        }
+
+        assert_eq!(r.len(), 10000);
+
+        for i in 0..10000 {
+            assert!(r.contains(i * 10));
+            assert!(r.contains(i * 10 + 4));
+            assert!(!r.contains(i * 10 + 5));
+            assert!(!r.contains(i * 10 + 9));
+        }
+    }
+
+    #[test]
+    fn smallest_largest() {
+        let mut r = RangeSet::new();
+        assert_eq!(r.smallest(), None);
+        assert_eq!(r.largest(), None);
+
+        r.insert_range(4..7);
+        r.insert_range(9..12);
+
+        assert_eq!(r.smallest(), Some(4));
+        assert_eq!(r.largest(), Some(11));
+    }
+
+    #[test]
+    fn insert_max() {
+        let mut r = RangeSet::new();
+
+        assert_eq!(r.insert(u64::MAX - 1), Ok(()));
+        assert_eq!(r.insert(u64::MAX), Err(Error::ValueOutOfRange));
+        assert_eq!(ranges(&r), vec![u64::MAX - 1..u64::MAX]);
+    }
+
+    #[test]
+    fn difference_spanning() {
+        let mut a = RangeSet::new();
+        a.insert_range(0..4);
+        a.insert_range(6..10);
+        a.insert_range(12..20);
+
+        // The first range covers parts of three ranges of `a`, and the last
+        // two fall within a single one.
+        let mut b = RangeSet::new();
+        b.insert_range(2..14);
+        b.insert_range(15..16);
+        b.insert_range(17..18);
+
+        assert_eq!(ranges(&a.difference(&b)), vec![0..2, 14..15, 16..17,
+                                                   18..20]);
+        assert_eq!(ranges(&b.difference(&a)), vec![4..6, 10..12]);
+    }
+}
@@ -25,11 +25,13 @@
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use ::Result;
+use ::Error;
+
+use frame;
 
 use std::cmp;
 use std::collections::hash_map;
-use std::collections::BinaryHeap;
-use std::collections::VecDeque;
+use std::collections::BTreeMap;
 
 #[derive(Default)]
 pub struct Stream {
@@ -42,8 +44,12 @@ impl Stream {
         Self::default()
     }
 
-    pub fn push_recv(&mut self, data: &[u8], off: usize) -> Result<()> {
-        self.recv.push(data, off)
+    // Buffers the data carried by a STREAM frame, including its FIN.
+    pub fn push_frame(&mut self, frame: &frame::Frame) -> Result<()> {
+        let (off, _, fin) = frame.stream_range().ok_or(Error::InvalidFrame)?;
+        let data = frame.stream_data().ok_or(Error::InvalidFrame)?;
+
+        self.recv.write(off, data, fin)
     }
 
     pub fn pop_recv(&mut self, data: &mut [u8]) -> Result<usize> {
@@ -57,6 +63,11 @@ impl Stream {
     pub fn can_read(&self) -> bool {
         self.recv.ready()
     }
+
+    // Whether all the data up to the peer's FIN was read.
+    pub fn is_fin(&self) -> bool {
+        self.recv.is_fin()
+    }
 }
 
 pub struct StreamIterator<'a> {
@@ -91,89 +102,218 @@ impl<'a> Iterator for StreamIterator<'a> {
     }
 }
 
+// How far past the read offset CRYPTO data is buffered, as CRYPTO frames
+// aren't subject to flow control.
+const MAX_CRYPTO_BUFFER: u64 = 65536;
+
+// Reassembles CRYPTO frames received out of order, so that the handshake
+// data can be fed to TLS in order.
+#[derive(Default)]
+pub struct CryptoBuffer {
+    recv: RecvBuf,
+}
+
+impl CryptoBuffer {
+    pub fn new() -> CryptoBuffer {
+        Self::default()
+    }
+
+    // Buffers the data carried by a CRYPTO frame.
+    pub fn push_frame(&mut self, frame: &frame::Frame) -> Result<()> {
+        match frame {
+            frame::Frame::Crypto { offset, data } => self.write(*offset, data),
+
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    // Buffers data received at the given offset. See `RecvBuf::write()`.
+    // Data ending more than MAX_CRYPTO_BUFFER bytes past the read offset
+    // results in CryptoBufferExceeded.
+    fn write(&mut self, off: u64, data: &[u8]) -> Result<()> {
+        if off.saturating_add(data.len() as u64) >
+           self.recv.off + MAX_CRYPTO_BUFFER {
+            return Err(Error::CryptoBufferExceeded);
+        }
+
+        self.recv.write(off, data, false)
+    }
+
+    // Returns all the contiguous data available at the current offset, and
+    // advances the offset past it. An empty slice is returned while there
+    // is a gap before the buffered data.
+    pub fn read_contiguous(&mut self) -> &[u8] {
+        self.recv.read_contiguous()
+    }
+}
+
+// Reassembles data received out of order, for both STREAM and CRYPTO
+// frames, so that it can be read in order.
+//
+// Data past the read offset is kept as received, in chunks that don't
+// overlap, so memory use depends on how much data is buffered rather than
+// on how far ahead of the read offset it is.
 #[derive(Default)]
 struct RecvBuf {
-    data: BinaryHeap<RangeBuf>,
-    off: usize,
-    len: usize,
+    // Chunks of data keyed by their offset, all of them past `off`.
+    data: BTreeMap<u64, Vec<u8>>,
+
+    // Offset of the next byte to be read.
+    off: u64,
+
+    // The end of the data received so far, including any gaps.
+    max_off: u64,
+
+    // The final size of the stream, once a FIN was received.
+    fin_off: Option<u64>,
+
+    // The data returned by the last `read_contiguous()` call.
+    read: Vec<u8>,
 }
 
 impl RecvBuf {
-    fn push(&mut self, data: &[u8], off: usize) -> Result<()> {
-        let buf = RangeBuf {
-            data: Vec::from(data),
-            off,
-        };
+    // Buffers data received at the given offset, with `fin` set if it ends
+    // the stream.
+    //
+    // Parts of it that were already received must be the same as before,
+    // otherwise InvalidFrame is returned. Data past the final size, or a
+    // FIN that changes it, results in FinalSizeError. Nothing is buffered
+    // on error.
+    fn write(&mut self, off: u64, data: &[u8], fin: bool) -> Result<()> {
+        let end = off + data.len() as u64;
+
+        if let Some(fin_off) = self.fin_off {
+            if end > fin_off || (fin && end != fin_off) {
+                return Err(Error::FinalSizeError);
+            }
+        }
+
+        if fin && self.max_off > end {
+            return Err(Error::FinalSizeError);
+        }
+
+        // Data that was already read can't be checked anymore, so it's just
+        // ignored.
+        let skip = cmp::min(self.off.saturating_sub(off), data.len() as u64);
+
+        let data = &data[skip as usize..];
+        let off = off + skip;
+
+        // Find the parts that were already received, from the last one.
+        let mut overlaps = Vec::new();
+
+        for (&start, buf) in self.data.range(..end).rev() {
+            let stop = start + buf.len() as u64;
+
+            if stop <= off {
+                break;
+            }
+
+            let from = cmp::max(start, off);
+            let to = cmp::min(stop, end);
+
+            let old = &buf[(from - start) as usize .. (to - start) as usize];
+            let new = &data[(from - off) as usize .. (to - off) as usize];
+
+            if old != new {
+                return Err(Error::InvalidFrame);
+            }
+
+            overlaps.push((from, to));
+        }
 
-        self.len = cmp::max(self.len, buf.off + buf.len());
+        // Only buffer the gaps between them.
+        let mut cur = off;
 
-        self.data.push(buf);
+        for (from, to) in overlaps.into_iter().rev() {
+            if cur < from {
+                let buf = data[(cur - off) as usize .. (from - off) as usize]
+                              .to_vec();
+                self.data.insert(cur, buf);
+            }
+
+            cur = to;
+        }
+
+        if cur < end {
+            self.data.insert(cur, data[(cur - off) as usize ..].to_vec());
+        }
+
+        self.max_off = cmp::max(self.max_off, end);
+
+        if fin {
+            self.fin_off = Some(end);
+        }
 
         Ok(())
     }
 
+    // Returns all the contiguous data available at the current offset, and
+    // advances the offset past it.
+    fn read_contiguous(&mut self) -> &[u8] {
+        self.read.clear();
+
+        while let Some(buf) = self.data.remove(&self.off) {
+            self.off += buf.len() as u64;
+
+            if self.read.is_empty() {
+                self.read = buf;
+            } else {
+                self.read.extend_from_slice(&buf);
+            }
+        }
+
+        &self.read
+    }
+
     fn pop(&mut self, out: &mut [u8]) -> Result<usize> {
-        let mut out_len = out.len();
-        let mut out_off = 0;
+        let mut len = 0;
 
-        while out_len > 0 && self.ready() {
-            let mut buf = match self.data.pop() {
+        while len < out.len() {
+            let mut buf = match self.data.remove(&self.off) {
                 Some(v) => v,
+
                 None => break,
             };
 
-            if buf.len() > out_len {
-                let new = RangeBuf {
-                    data: buf.data.split_off(out_len),
-                    off: buf.off + out_len,
-                };
-
-                self.data.push(new);
-            }
+            let n = cmp::min(buf.len(), out.len() - len);
 
-            let out = &mut out[out_off .. out_off + buf.len()];
-            out.copy_from_slice(&buf.data);
+            out[len .. len + n].copy_from_slice(&buf[..n]);
 
-            out_len -= buf.len();
-            out_off += buf.len();
+            len += n;
+            self.off += n as u64;
 
-            self.off += buf.len();
-            self.len -= buf.len();
+            if n < buf.len() {
+                self.data.insert(self.off, buf.split_off(n));
+            }
         }
 
-        Ok(out_off)
+        Ok(len)
     }
 
     fn ready(&self) -> bool {
-        let buf = match self.data.peek() {
-            Some(v) => v,
-            None => return false,
-        };
-
-        buf.off == self.off
+        self.data.contains_key(&self.off)
     }
 
-    fn len(&self) -> usize {
-        self.len
+    // Whether all the data up to the final size was read.
+    fn is_fin(&self) -> bool {
+        self.fin_off == Some(self.off)
     }
 }
 
 #[derive(Default)]
 struct SendBuf {
-    data: VecDeque<RangeBuf>,
     off: usize,
 }
 
 impl SendBuf {
+    // Returns the stream offset the data starts at.
     fn push(&mut self, data: &[u8]) -> Result<usize> {
-        let buf = RangeBuf {
-            data: Vec::from(data),
-            off: self.off,
-        };
+        let off = self.off;
 
-        self.data.push_back(buf);
+        self.off += data.len();
 
-        Ok(self.off)
+        Ok(off)
     }
 
     // fn peek(&mut self, out: &mut [u8]) -> Result<usize> {
@@ -185,37 +325,6 @@ impl SendBuf {
     // }
 }
 
-#[derive(Eq)]
-struct RangeBuf {
-    data: Vec<u8>,
-    off: usize,
-}
-
-impl RangeBuf {
-    pub fn len(&self) -> usize {
-        self.data.len()
-    }
-}
-
-impl Ord for RangeBuf {
-    fn cmp(&self, other: &RangeBuf) -> cmp::Ordering {
-        // Invert ordering to implement min-heap.
-        self.off.cmp(&other.off).reverse()
-    }
-}
-
-impl PartialOrd for RangeBuf {
-    fn partial_cmp(&self, other: &RangeBuf) -> Option<cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for RangeBuf {
-    fn eq(&self, other: &RangeBuf) -> bool {
-        self.off == other.off
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -223,7 +332,7 @@ mod tests {
     #[test]
     fn empty_read() {
         let mut buf = RecvBuf::default();
-        assert_eq!(buf.len(), 0);
+        assert_eq!(buf.max_off - buf.off, 0);
 
         let mut out: [u8; 10] = [0; 10];
         let read = buf.pop(&mut out);
@@ -233,30 +342,30 @@ mod tests {
     #[test]
     fn ordered_read() {
         let mut buf = RecvBuf::default();
-        assert_eq!(buf.len(), 0);
+        assert_eq!(buf.max_off - buf.off, 0);
 
         let first: [u8; 5] = *b"hello";
         let second: [u8; 5] = *b"world";
         let third: [u8; 9] = *b"something";
 
-        assert!(buf.push(&second, 5).is_ok());
-        assert_eq!(buf.len(), 10);
+        assert!(buf.write(5, &second, false).is_ok());
+        assert_eq!(buf.max_off - buf.off, 10);
 
         let mut out: [u8; 10] = [0; 10];
         let read = buf.pop(&mut out);
         assert_eq!(read, Ok(0));
 
-        assert!(buf.push(&third, 10).is_ok());
-        assert_eq!(buf.len(), 19);
+        assert!(buf.write(10, &third, false).is_ok());
+        assert_eq!(buf.max_off - buf.off, 19);
 
-        assert!(buf.push(&first, 0).is_ok());
-        assert_eq!(buf.len(), 19);
+        assert!(buf.write(0, &first, false).is_ok());
+        assert_eq!(buf.max_off - buf.off, 19);
 
         let mut out: [u8; 20] = [0; 20];
         let read = buf.pop(&mut out);
         assert_eq!(read, Ok(19));
         assert_eq!(&out[..19], b"helloworldsomething");
-        assert_eq!(buf.len(), 0);
+        assert_eq!(buf.max_off - buf.off, 0);
 
         let read = buf.pop(&mut out);
         assert_eq!(read, Ok(0));
@@ -265,27 +374,276 @@ mod tests {
     #[test]
     fn split_read() {
         let mut buf = RecvBuf::default();
-        assert_eq!(buf.len(), 0);
+        assert_eq!(buf.max_off - buf.off, 0);
 
         let first: [u8; 9] = *b"something";
         let second: [u8; 10] = *b"helloworld";
 
-        assert!(buf.push(&second, 9).is_ok());
-        assert_eq!(buf.len(), 19);
+        assert!(buf.write(9, &second, false).is_ok());
+        assert_eq!(buf.max_off - buf.off, 19);
 
-        assert!(buf.push(&first, 0).is_ok());
-        assert_eq!(buf.len(), 19);
+        assert!(buf.write(0, &first, false).is_ok());
+        assert_eq!(buf.max_off - buf.off, 19);
 
         let mut out: [u8; 14] = [0; 14];
         let read = buf.pop(&mut out);
         assert_eq!(read, Ok(14));
         assert_eq!(&out, b"somethinghello");
-        assert_eq!(buf.len(), 5);
+        assert_eq!(buf.max_off - buf.off, 5);
 
         let mut out: [u8; 10] = [0; 10];
         let read = buf.pop(&mut out);
         assert_eq!(read, Ok(5));
         assert_eq!(&out[0..5], b"world");
-        assert_eq!(buf.len(), 0);
+        assert_eq!(buf.max_off - buf.off, 0);
+    }
+
+    #[test]
+    fn overlapping_write() {
+        let mut buf = RecvBuf::default();
+
+        assert!(buf.write(0, b"hello", false).is_ok());
+        assert!(buf.write(3, b"loworld", false).is_ok());
+        assert_eq!(buf.max_off - buf.off, 10);
+
+        assert_eq!(buf.write(8, b"lD", false), Err(Error::InvalidFrame));
+        assert_eq!(buf.max_off - buf.off, 10);
+
+        assert_eq!(buf.read_contiguous(), b"helloworld");
+        assert_eq!(buf.max_off - buf.off, 0);
+    }
+
+    #[test]
+    fn duplicate_write() {
+        let mut buf = RecvBuf::default();
+
+        assert!(buf.write(0, b"hello", false).is_ok());
+        assert!(buf.write(0, b"hello", false).is_ok());
+        assert_eq!(buf.max_off - buf.off, 5);
+
+        let mut out: [u8; 10] = [0; 10];
+        assert_eq!(buf.pop(&mut out), Ok(5));
+        assert_eq!(&out[..5], b"hello");
+
+        // Already read data is ignored.
+        assert!(buf.write(0, b"hello", false).is_ok());
+        assert!(!buf.ready());
+        assert_eq!(buf.pop(&mut out), Ok(0));
+    }
+
+    #[test]
+    fn fin_before_data() {
+        let mut buf = RecvBuf::default();
+
+        assert!(buf.write(5, b"world", true).is_ok());
+        assert!(!buf.is_fin());
+        assert!(!buf.ready());
+
+        assert_eq!(buf.write(10, b"!", false), Err(Error::FinalSizeError));
+        assert_eq!(buf.write(0, b"hel", true), Err(Error::FinalSizeError));
+        assert_eq!(buf.write(5, b"worl", true), Err(Error::FinalSizeError));
+
+        assert!(buf.write(0, b"hello", false).is_ok());
+        assert!(!buf.is_fin());
+
+        assert_eq!(buf.read_contiguous(), b"helloworld");
+        assert!(buf.is_fin());
+
+        // A retransmission of the FIN is fine.
+        assert!(buf.write(10, b"", true).is_ok());
+        assert!(buf.is_fin());
+    }
+
+    #[test]
+    fn far_offset() {
+        let mut buf = RecvBuf::default();
+
+        // Only what was received is buffered, not the gap before it.
+        assert!(buf.write(1 << 44, b"x", false).is_ok());
+        assert!(buf.write(1 << 50, b"", false).is_ok());
+        assert!(buf.write((1 << 62) - 1, b"y", false).is_ok());
+
+        assert!(!buf.ready());
+        assert_eq!(buf.data.len(), 2);
+        assert_eq!(buf.read_contiguous(), b"");
+
+        let mut stream = Stream::new();
+
+        let frame = frame::FrameOwned::stream(0, 1 << 44, b"x".to_vec(), false);
+        assert!(stream.push_frame(&frame).is_ok());
+        assert!(!stream.can_read());
+    }
+
+    #[test]
+    fn partial_pop() {
+        let mut buf = RecvBuf::default();
+
+        assert!(buf.write(0, b"hello", false).is_ok());
+        assert!(buf.write(5, b"world", false).is_ok());
+
+        let mut out: [u8; 3] = [0; 3];
+        assert_eq!(buf.pop(&mut out), Ok(3));
+        assert_eq!(&out, b"hel");
+
+        // Data that was partially read is still checked.
+        assert_eq!(buf.write(3, b"lO", false), Err(Error::InvalidFrame));
+        assert!(buf.write(0, b"hello", false).is_ok());
+
+        assert_eq!(buf.read_contiguous(), b"loworld");
+        assert_eq!(buf.max_off - buf.off, 0);
+    }
+
+    #[test]
+    fn fin_smaller_than_received() {
+        let mut buf = RecvBuf::default();
+
+        assert!(buf.write(0, b"helloworld", false).is_ok());
+        assert_eq!(buf.write(0, b"hello", true), Err(Error::FinalSizeError));
+
+        assert_eq!(buf.read_contiguous(), b"helloworld");
+
+        // An empty FIN frame once everything was read.
+        assert!(buf.write(10, b"", true).is_ok());
+        assert!(buf.is_fin());
+    }
+
+    #[test]
+    fn stream_push_frame() {
+        let mut stream = Stream::new();
+
+        let world = frame::FrameOwned::stream(0, 5, b"world".to_vec(), true);
+        let hello = frame::FrameOwned::stream(0, 0, b"hello".to_vec(), false);
+
+        assert!(stream.push_frame(&world).is_ok());
+        assert!(!stream.can_read());
+        assert!(!stream.is_fin());
+
+        assert!(stream.push_frame(&hello).is_ok());
+        assert!(stream.can_read());
+
+        let mut out: [u8; 10] = [0; 10];
+        assert_eq!(stream.pop_recv(&mut out), Ok(10));
+        assert_eq!(&out, b"helloworld");
+        assert!(stream.is_fin());
+
+        assert_eq!(stream.push_frame(&frame::Frame::ping()),
+                   Err(Error::InvalidFrame));
+    }
+
+    #[test]
+    fn crypto_out_of_order() {
+        let mut buf = CryptoBuffer::new();
+        assert_eq!(buf.read_contiguous(), b"");
+
+        let hello = frame::Frame::Crypto {
+            offset: 0,
+            data: b"hello".to_vec().into(),
+        };
+
+        let world = frame::Frame::Crypto {
+            offset: 5,
+            data: b"world".to_vec().into(),
+        };
+
+        assert!(buf.push_frame(&world).is_ok());
+        assert_eq!(buf.read_contiguous(), b"");
+        assert_eq!(buf.recv.off, 0);
+
+        assert!(buf.push_frame(&hello).is_ok());
+        assert_eq!(buf.read_contiguous(), b"helloworld");
+        assert_eq!(buf.recv.off, 10);
+
+        assert_eq!(buf.push_frame(&frame::Frame::ping()),
+                   Err(Error::InvalidFrame));
+    }
+
+    #[test]
+    fn crypto_read_contiguous() {
+        let mut buf = CryptoBuffer::new();
+        assert_eq!(buf.read_contiguous(), b"");
+
+        assert!(buf.write(10, b"something").is_ok());
+        assert!(buf.write(0, b"hello").is_ok());
+
+        assert_eq!(buf.read_contiguous(), b"hello");
+        assert_eq!(buf.recv.off, 5);
+        assert_eq!(buf.recv.data.len(), 1);
+
+        assert_eq!(buf.read_contiguous(), b"");
+        assert_eq!(buf.recv.off, 5);
+
+        assert!(buf.write(5, b"world").is_ok());
+        assert!(buf.recv.ready());
+
+        assert_eq!(buf.read_contiguous(), b"worldsomething");
+        assert_eq!(buf.recv.off, 19);
+
+        assert_eq!(buf.read_contiguous(), b"");
+        assert!(buf.recv.data.is_empty());
+    }
+
+    #[test]
+    fn crypto_overlap() {
+        let mut buf = CryptoBuffer::new();
+
+        assert!(buf.write(0, b"hello").is_ok());
+        assert!(buf.write(3, b"loworld").is_ok());
+        assert!(buf.write(14, b"thing").is_ok());
+
+        // Same data, spanning both the buffered ranges and the gap.
+        assert!(buf.write(8, b"ldsomething").is_ok());
+
+        assert_eq!(buf.read_contiguous(), b"helloworldsomething");
+
+        // Data that was already read is not checked again.
+        assert!(buf.write(0, b"HELLO").is_ok());
+        assert!(buf.write(17, b"NGS").is_ok());
+
+        assert_eq!(buf.read_contiguous(), b"S");
+        assert_eq!(buf.recv.off, 20);
+    }
+
+    #[test]
+    fn crypto_overlap_inconsistent() {
+        let mut buf = CryptoBuffer::new();
+
+        assert!(buf.write(5, b"world").is_ok());
+
+        assert_eq!(buf.write(0, b"hellow0rld"), Err(Error::InvalidFrame));
+        assert_eq!(buf.write(9, b"D"), Err(Error::InvalidFrame));
+
+        // Nothing was written by the rejected writes.
+        assert_eq!(buf.recv.data.len(), 1);
+
+        assert!(buf.write(0, b"hello").is_ok());
+        assert_eq!(buf.read_contiguous(), b"helloworld");
+    }
+
+    #[test]
+    fn crypto_buffer_limit() {
+        let mut buf = CryptoBuffer::new();
+
+        let max = MAX_CRYPTO_BUFFER;
+
+        assert!(buf.write(max - 5, b"hello").is_ok());
+        assert_eq!(buf.write(max - 4, b"hello"),
+                   Err(Error::CryptoBufferExceeded));
+        assert_eq!(buf.write((1 << 62) - 1, b""),
+                   Err(Error::CryptoBufferExceeded));
+
+        // The limit moves along with the read offset.
+        assert!(buf.write(0, b"hello").is_ok());
+        assert_eq!(buf.read_contiguous(), b"hello");
+        assert!(buf.write(max, b"world").is_ok());
+    }
+
+    #[test]
+    fn send_offsets() {
+        let mut stream = Stream::new();
+
+        assert_eq!(stream.push_send(b"hello"), Ok(0));
+        assert_eq!(stream.push_send(b""), Ok(5));
+        assert_eq!(stream.push_send(b"world"), Ok(5));
+        assert_eq!(stream.push_send(b"!"), Ok(10));
     }
 }
@@ -0,0 +1,80 @@
+// Copyright (c) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright
+//       notice, this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+extern crate quiche;
+
+use quiche::frame;
+use quiche::octets;
+
+// Malformed inputs that the frame parser must handle without panicking,
+// whether that results in frames or in an error. Inputs found to crash the
+// parser (e.g. by fuzzing) should be added here once fixed.
+static CORPUS: &[&[u8]] = &[
+    // Truncated ACK frame, missing the first ACK block.
+    &[0x0d, 0x05, 0x00, 0x00],
+
+    // ACK frame whose first ACK block is larger than the largest
+    // acknowledged packet.
+    &[0x0d, 0x05, 0x00, 0x00, 0x0a],
+
+    // ACK frame with a huge block count but no blocks.
+    &[0x0d, 0x05, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00],
+
+    // Truncated varint.
+    &[0x0d, 0xc0, 0x00],
+
+    // STREAM frame with a huge declared length.
+    &[0x12, 0x04, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+
+    // CONNECTION_CLOSE frame with a huge declared reason length.
+    &[0x02, 0x00, 0x00, 0x00, 0xbf, 0xff, 0xff, 0xff, 0x01],
+
+    // NEW_CONNECTION_ID frame missing the reset token.
+    &[0x0b, 0x00, 0x04, 0xba, 0xdc, 0x0f, 0xfe],
+];
+
+#[test]
+fn regressions() {
+    for input in CORPUS {
+        let mut buf = input.to_vec();
+
+        let mut b = octets::Bytes::new(&mut buf);
+
+        while b.cap() > 0 {
+            if frame::Frame::from_bytes(&mut b).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+#[test]
+fn fuzz_roundtrip() {
+    for input in CORPUS {
+        frame::fuzz_roundtrip(input);
+    }
+}